@@ -0,0 +1,309 @@
+// Settings read from capstone.toml (or --config <path>), each of which can be
+// overridden by a CAPSTONE_* environment variable. Command line flags in turn
+// override both, so an existing invocation behaves the same with a config file
+// around.
+//
+// Only the flat part of TOML is understood: `key = value` lines whose value is
+// a basic string or an integer, blank lines and `#` comments. That
+// covers every setting here without pulling in a TOML parser.
+
+use bitcoincore_rpc::{Error, Result};
+
+// Looked up in the working directory when no --config is given
+pub const DEFAULT_PATH: &str = "capstone.toml";
+
+#[derive(Default)]
+pub struct Config {
+    pub rpc_url: Option<String>,
+    pub rpc_user: Option<String>,
+    pub rpc_password: Option<String>,
+    pub network: Option<String>,
+    pub miner_wallet: Option<String>,
+    pub trader_wallet: Option<String>,
+    pub output_path: Option<String>,
+    // Seconds, integers in the file
+    pub rpc_timeout: Option<u64>,
+    pub rpc_slow_timeout: Option<u64>,
+}
+
+// A value on the right of `=`
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+}
+
+// Config file keys and the environment variables overriding them
+const KEYS: [(&str, &str); 9] = [
+    ("rpc_url", "CAPSTONE_RPC_URL"),
+    ("rpc_user", "CAPSTONE_RPC_USER"),
+    ("rpc_password", "CAPSTONE_RPC_PASSWORD"),
+    ("network", "CAPSTONE_NETWORK"),
+    ("miner_wallet", "CAPSTONE_MINER_WALLET"),
+    ("trader_wallet", "CAPSTONE_TRADER_WALLET"),
+    ("output_path", "CAPSTONE_OUTPUT_PATH"),
+    ("rpc_timeout", "CAPSTONE_RPC_TIMEOUT"),
+    ("rpc_slow_timeout", "CAPSTONE_RPC_SLOW_TIMEOUT"),
+];
+
+impl Config {
+    // The config at `path`, which has to exist, or at DEFAULT_PATH if that
+    // exists, with the environment applied on top
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let mut config = Config::default();
+        let text = match path {
+            Some(path) => Some(std::fs::read_to_string(path).map_err(|e| {
+                Error::ReturnedError(format!("Can't read config file {path}: {e}"))
+            })?),
+            None => std::fs::read_to_string(DEFAULT_PATH).ok(),
+        };
+        if let Some(text) = text {
+            let source = path.unwrap_or(DEFAULT_PATH);
+            for (key, value) in parse(&text, source)? {
+                config
+                    .set(&key, value, false)
+                    .map_err(|what| Error::ReturnedError(format!("{source}: {what}")))?;
+            }
+        }
+        for (key, var) in KEYS {
+            if let Ok(value) = std::env::var(var) {
+                config
+                    .set(key, Value::String(value), true)
+                    .map_err(|what| Error::ReturnedError(format!("{var}: {what}")))?;
+            }
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: Value, from_env: bool) -> std::result::Result<(), String> {
+        // The timeouts are numbers, every other setting is text. Environment
+        // variables are text too, so for them the number is parsed here.
+        if let "rpc_timeout" | "rpc_slow_timeout" = key {
+            let seconds = match value {
+                Value::Integer(n) => u64::try_from(n).ok(),
+                Value::String(s) if from_env => s.trim().parse().ok(),
+                _ => None,
+            }
+            .ok_or_else(|| format!("{key} must be a whole number of seconds"))?;
+            match key {
+                "rpc_timeout" => self.rpc_timeout = Some(seconds),
+                _ => self.rpc_slow_timeout = Some(seconds),
+            }
+            return Ok(());
+        }
+        let slot = match key {
+            "rpc_url" => &mut self.rpc_url,
+            "rpc_user" => &mut self.rpc_user,
+            "rpc_password" => &mut self.rpc_password,
+            "network" => &mut self.network,
+            "miner_wallet" => &mut self.miner_wallet,
+            "trader_wallet" => &mut self.trader_wallet,
+            "output_path" => &mut self.output_path,
+            _ => return Err(format!("unknown setting {key}")),
+        };
+        match value {
+            Value::String(value) => *slot = Some(value),
+            _ => return Err(format!("{key} must be a quoted string")),
+        }
+        Ok(())
+    }
+}
+
+// The `key = value` pairs of a flat TOML document, in order
+fn parse(text: &str, source: &str) -> Result<Vec<(String, Value)>> {
+    let mut pairs = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let invalid =
+            |what: &str| Error::ReturnedError(format!("{source} line {}: {what}", number + 1));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(invalid(
+                "tables are not supported, settings go at the top level",
+            ));
+        }
+        let (key, rest) = line
+            .split_once('=')
+            .ok_or_else(|| invalid("expected key = value"))?;
+        let value = parse_value(rest.trim())
+            .ok_or_else(|| invalid("expected a quoted string or an integer"))?;
+        pairs.push((key.trim().to_string(), value));
+    }
+    Ok(pairs)
+}
+
+// A TOML basic string or decimal integer, optionally followed by a comment
+fn parse_value(text: &str) -> Option<Value> {
+    if text.starts_with('"') {
+        return parse_string(text).map(Value::String);
+    }
+    let token = match text.split_once('#') {
+        Some((token, _)) => token.trim(),
+        None => text,
+    };
+    parse_integer(token).map(Value::Integer)
+}
+
+// A TOML decimal integer: an optional sign, digits and single underscores
+// between digits, no leading zeros
+fn parse_integer(token: &str) -> Option<i64> {
+    let digits = token.strip_prefix(['+', '-']).unwrap_or(token);
+    let valid = !digits.is_empty()
+        && !digits.starts_with('_')
+        && !digits.ends_with('_')
+        && !digits.contains("__")
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '_')
+        && (digits == "0" || !digits.starts_with('0'));
+    if !valid {
+        return None;
+    }
+    token.replace('_', "").parse().ok()
+}
+
+// A TOML basic string, optionally followed by a comment. Handles the escapes
+// a path or password is likely to need.
+fn parse_string(text: &str) -> Option<String> {
+    let mut chars = text.strip_prefix('"')?.chars();
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => break,
+            '\\' => value.push(match chars.next()? {
+                '"' => '"',
+                '\\' => '\\',
+                'n' => '\n',
+                't' => '\t',
+                _ => return None,
+            }),
+            c => value.push(c),
+        }
+    }
+    let rest = chars.as_str().trim();
+    (rest.is_empty() || rest.starts_with('#')).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_string_reads_a_basic_string() {
+        assert_eq!(parse_string(r#""bitcoin""#).unwrap(), "bitcoin");
+        assert_eq!(parse_string(r#""""#).unwrap(), "");
+    }
+
+    #[test]
+    fn parse_string_unescapes() {
+        assert_eq!(parse_string(r#""a\"b\\c\nd\te""#).unwrap(), "a\"b\\c\nd\te");
+    }
+
+    #[test]
+    fn parse_string_allows_a_trailing_comment() {
+        assert_eq!(
+            parse_string(r#""pass#word" # the # is kept"#).unwrap(),
+            "pass#word"
+        );
+    }
+
+    #[test]
+    fn parse_string_rejects_malformed_strings() {
+        assert_eq!(parse_string(r#""unterminated"#), None);
+        assert_eq!(parse_string(r#""bad \q escape""#), None);
+        assert_eq!(parse_string(r#""value" trailing"#), None);
+        assert_eq!(parse_string("bare"), None);
+    }
+
+    #[test]
+    fn parse_integer_reads_signs_and_underscores() {
+        assert_eq!(parse_integer("30"), Some(30));
+        assert_eq!(parse_integer("+30"), Some(30));
+        assert_eq!(parse_integer("-30"), Some(-30));
+        assert_eq!(parse_integer("1_000"), Some(1000));
+        assert_eq!(parse_integer("0"), Some(0));
+        assert_eq!(parse_integer("-0"), Some(0));
+    }
+
+    #[test]
+    fn parse_integer_rejects_malformed_integers() {
+        assert_eq!(parse_integer("030"), None);
+        assert_eq!(parse_integer("_30"), None);
+        assert_eq!(parse_integer("30_"), None);
+        assert_eq!(parse_integer("1__000"), None);
+        assert_eq!(parse_integer("+"), None);
+        assert_eq!(parse_integer("--30"), None);
+        assert_eq!(parse_integer("3.0"), None);
+        assert_eq!(parse_integer("true"), None);
+    }
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let text =
+            "# node\n\nrpc_url = \"http://127.0.0.1:18443\"\n  # timeouts\nrpc_timeout = 30 # s\n";
+        assert_eq!(
+            parse(text, "capstone.toml").unwrap(),
+            [
+                (
+                    "rpc_url".to_string(),
+                    Value::String("http://127.0.0.1:18443".to_string())
+                ),
+                ("rpc_timeout".to_string(), Value::Integer(30)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_tables() {
+        let error = parse("network = \"regtest\"\n[rpc]\n", "capstone.toml").unwrap_err();
+        assert!(error.to_string().contains("capstone.toml line 2: tables"));
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        let text = "network = \"regtest\"\n\nrpc_user alice\n";
+        let error = parse(text, "capstone.toml").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("capstone.toml line 3: expected key = value"));
+
+        let text = "network = \"regtest\"\nrpc_timeout = true\n";
+        let error = parse(text, "capstone.toml").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("capstone.toml line 2: expected a quoted"));
+    }
+
+    #[test]
+    fn set_wants_strings_for_text_settings() {
+        let mut config = Config::default();
+        assert!(config.set("network", Value::Integer(1), false).is_err());
+        assert!(config
+            .set("nework", Value::String("regtest".to_string()), false)
+            .is_err());
+        config
+            .set("network", Value::String("regtest".to_string()), false)
+            .unwrap();
+        assert_eq!(config.network.as_deref(), Some("regtest"));
+    }
+
+    #[test]
+    fn set_wants_whole_seconds_for_timeouts() {
+        let mut config = Config::default();
+        assert!(config
+            .set("rpc_timeout", Value::Integer(-1), false)
+            .is_err());
+        // Only the environment spells numbers as text
+        assert!(config
+            .set("rpc_timeout", Value::String("30".to_string()), false)
+            .is_err());
+        config
+            .set("rpc_timeout", Value::Integer(30), false)
+            .unwrap();
+        config
+            .set("rpc_slow_timeout", Value::String(" 120 ".to_string()), true)
+            .unwrap();
+        assert_eq!(config.rpc_timeout, Some(30));
+        assert_eq!(config.rpc_slow_timeout, Some(120));
+    }
+}
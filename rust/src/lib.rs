@@ -0,0 +1,699 @@
+// The building blocks of the capstone flow: wallet setup, mining, sending and
+// reporting against a Bitcoin Core node. main.rs wires them to the command
+// line; other programs can call them directly.
+
+use bitcoincore_rpc::bitcoin::{Amount, Denomination, Network};
+use bitcoincore_rpc::json::EstimateMode;
+use bitcoincore_rpc::jsonrpc::simple_http::{self, SimpleHttpTransport};
+use bitcoincore_rpc::jsonrpc::{Request, Response, Transport};
+use bitcoincore_rpc::{Auth, Client};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Set when stdout is reserved for machine-readable output (e.g. --field)
+pub static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+// Progress output. Goes to stdout normally, and to stderr when stdout is reserved.
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {{
+        let line = $crate::log_line("info", &format!($($arg)*));
+        if $crate::STDOUT_RESERVED.load(::std::sync::atomic::Ordering::Relaxed) {
+            eprintln!("{line}")
+        } else {
+            println!("{line}")
+        }
+    }};
+}
+
+// Set by --log-format json: every status and debug line becomes a JSON object
+// with a timestamp, level and the phase of the run it was printed in
+pub static LOG_JSON: AtomicBool = AtomicBool::new(false);
+
+// The part of the flow we're in, see `enter_phase`
+static PHASE: Mutex<&str> = Mutex::new("startup");
+
+// Mark the start of a phase of the run (wallet setup, mining, send, report), so
+// the log lines after it can be told apart
+pub fn enter_phase(phase: &'static str) {
+    *PHASE.lock().unwrap_or_else(|e| e.into_inner()) = phase;
+    debug(&format!("Entering phase {phase}"));
+}
+
+// `message` as it should be printed at `level` ("info" or "debug")
+pub fn log_line(level: &str, message: &str) -> String {
+    if !LOG_JSON.load(Ordering::Relaxed) {
+        return match level {
+            "info" => message.to_string(),
+            _ => format!("[{level}] {message}"),
+        };
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64());
+    let phase = *PHASE.lock().unwrap_or_else(|e| e.into_inner());
+    serde_json::json!({
+        "ts": timestamp,
+        "level": level,
+        "phase": phase,
+        "message": message,
+    })
+    .to_string()
+}
+
+// Declared after `status!` so the modules can use it
+pub mod config;
+pub mod mining;
+pub mod psbt;
+pub mod report;
+pub mod rpc;
+pub mod transaction;
+pub mod wallet;
+
+// Node access params
+pub const RPC_URL: &str = "http://127.0.0.1:18443"; // Default regtest RPC port
+pub const RPC_USER: &str = "alice";
+pub const RPC_PASS: &str = "password";
+
+// Fees above this are almost certainly a mistake on regtest
+pub const DEFAULT_MAX_FEE_BTC: &str = "0.01";
+
+// Set by --debug. Gates the extra explanations printed through `debug`.
+pub static DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn debug(message: &str) {
+    if DEBUG.load(Ordering::Relaxed) {
+        eprintln!("{}", log_line("debug", message));
+    }
+}
+
+pub fn required_value(flag: &str, value: Option<String>) -> bitcoincore_rpc::Result<String> {
+    value.ok_or_else(|| bitcoincore_rpc::Error::ReturnedError(format!("{flag} requires a value")))
+}
+
+pub fn parse_number<T: std::str::FromStr>(
+    flag: &str,
+    value: Option<String>,
+) -> bitcoincore_rpc::Result<T> {
+    let value = required_value(flag, value)?;
+    value.parse().map_err(|_| {
+        bitcoincore_rpc::Error::ReturnedError(format!("Invalid number for {flag}: {value}"))
+    })
+}
+
+// A BTC amount, optionally with its unit spelled out: `5.5`, `5.5btc` and
+// `550000000sat` are all the same amount
+pub fn parse_amount(flag: &str, value: Option<String>) -> bitcoincore_rpc::Result<Amount> {
+    let value = required_value(flag, value)?;
+    let lower = value.trim().to_lowercase();
+    let (number, denomination) = match lower.strip_suffix("btc") {
+        Some(number) => (number, Denomination::Bitcoin),
+        None => match lower.strip_suffix("sats").or(lower.strip_suffix("sat")) {
+            Some(number) => (number, Denomination::Satoshi),
+            None => (lower.as_str(), Denomination::Bitcoin),
+        },
+    };
+    Amount::from_str_in(number.trim(), denomination).map_err(|e| {
+        bitcoincore_rpc::Error::ReturnedError(format!("Invalid amount for {flag}: {value} ({e})"))
+    })
+}
+
+// What to do once connected. Without a subcommand we run the full capstone flow.
+pub enum Command {
+    // send (or no subcommand): mine what's missing, pay the Trader, report
+    Run,
+    // setup: create or load the wallets and stop
+    Setup,
+    // mine --blocks <n>
+    Mine {
+        blocks: u64,
+    },
+    // report <txid>
+    Report {
+        txid: String,
+    },
+    // preview-addresses --count <n>
+    PreviewAddresses {
+        count: u32,
+    },
+    // heartbeat --interval <secs>
+    Heartbeat {
+        interval: u64,
+    },
+    // block <height|hash>
+    Block {
+        id: String,
+    },
+    // mempool-info
+    MempoolInfo,
+    // bumpfee <txid> [--fee-rate <sat/vB>]: replace an unconfirmed send
+    BumpFee {
+        txid: String,
+    },
+    // cpfp <txid> [--fee-rate <sat/vB>]: speed up a payment the Trader received
+    Cpfp {
+        txid: String,
+    },
+    // psbt create|sign|finalize [<file|base64>]: the --psbt flow one step at a time,
+    // psbt inspect <file|base64>: what a PSBT holds and still lacks
+    Psbt {
+        action: String,
+        input: Option<String>,
+    },
+    // pay-many <file>: one transaction paying every address in a JSON or CSV file
+    PayMany {
+        path: String,
+    },
+    // verify [--file <path>]: check an out.txt against the chain
+    Verify {
+        path: Option<String>,
+    },
+    // wait-for-node: return once the node is ready, see --wait-for-node
+    WaitForNode,
+    // repl
+    Repl,
+    // scan-balance --descriptor <desc>
+    ScanBalance {
+        descriptor: String,
+    },
+    // sign-message --address <addr> --message <text>
+    SignMessage {
+        address: String,
+        message: String,
+    },
+    // verify-message --address <addr> --message <text> --signature <base64>
+    VerifyMessage {
+        address: String,
+        message: String,
+        signature: String,
+    },
+}
+
+// Output format for commands that print structured data (--format)
+#[derive(PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    // A prose sentence describing the run, for demos and logs
+    Summary,
+    // Binary report per proto/tx_report.proto, written to --out
+    Protobuf,
+    // One row per run appended to --out, with a header on the first
+    Csv,
+}
+
+// Command line options. Everything is optional so the plain `cargo run` used by
+// the autograder keeps working unchanged.
+pub struct Options {
+    pub command: Command,
+    pub format: Format,
+    pub max_fee: Amount,                     // --max-fee <btc>
+    pub yes: bool,                           // --yes: broadcast even if the fee guard trips
+    pub recipients_file: Option<String>, // --recipients-file <path>: payees instead of --recipients, JSON or .csv
+    pub min_confirmations: Option<usize>, // --min-confirmations <n>: minconf for balances
+    pub field: Option<String>,           // --field <name>: print only this report field
+    pub trader_blank: bool,              // --trader-blank: create Trader without keys
+    pub trader_watch_only: bool,         // --trader-watch-only: Trader with private keys disabled
+    pub trader_multisig: Option<String>, // --trader-multisig <descriptor>: Trader is a multisig
+    pub deterministic: bool,             // --deterministic: seed new wallets from fixed keys
+    pub network: Network,                // --network <name>: chain the node runs on
+    pub cookie_file: Option<String>,     // --cookie-file <path> or derived from --datadir
+    pub webhook: Option<String>,         // --webhook <url>: POST the JSON report here
+    pub fee_rate: Option<f64>,           // --fee-rate <sat/vB>: explicit fee rate for the send
+    pub fee: Option<Amount>,             // --fee <btc>: absolute fee for the send
+    pub subtract_fee: bool,              // --subtract-fee: the payment itself pays the fee
+    pub replaceable: bool,               // off with --no-rbf: signal BIP 125 replaceability
+    pub psbt: bool,                      // --psbt: send through the PSBT flow
+    pub comment: Option<String>,         // --comment <text>: wallet note on the send
+    pub comment_to: Option<String>,      // --comment-to <text>: wallet note on the recipient
+    pub append: bool,                    // --append: add a JSON line to out.txt per run
+    pub run_id: Option<String>,          // --run-id <id|auto>: suffix for the wallet names
+    pub estimate_mode: Option<EstimateMode>, // --estimate-mode <mode>: fee estimation for sends
+    pub report_cdd: bool,                // --report-cdd: add coin days destroyed to the report
+    pub snapshot_dir: Option<String>,    // --snapshot-dir <dir>: chain state before/after
+    pub metrics_out: Option<String>,     // --metrics-out <path>: Prometheus metrics of the run
+    pub repeat: bool,                    // --loop: run the flow until Ctrl-C
+    pub loop_delay: Duration,            // --loop-delay <secs>: pause between --loop runs
+    pub rpc_url: String,                 // --rpc-url <url>: node RPC endpoint, without wallet
+    pub rpc_user: Option<String>,        // --rpc-user <name>: RPC user instead of the default
+    pub rpc_password: Option<String>,    // --rpc-password <pass>: password for --rpc-user
+    pub miner_wallet: String,            // --miner-wallet <name>: wallet that mines and pays
+    pub trader_wallet: String,           // --trader-wallet <name>: wallet that gets paid
+    pub amount: Amount,                  // --amount <btc>: what the Miner pays the Trader
+    pub recipients: u64, // --recipients <n>: fresh Trader addresses paid --amount each
+    pub output_path: String, // output_path in capstone.toml: where out.txt goes
+    pub test_accept: bool, // --test-accept: testmempoolaccept before sending
+    pub price: Option<f64>, // --price <usd_per_btc>: add USD values to the report
+    pub trace_change: bool, // --trace-change: write how the change was picked
+    pub confirmations: u64, // --confirmations <n>: blocks to mine after the send
+    pub no_send: bool,   // --no-send: only set up the Miner and mine
+    pub target_balance: Option<Amount>, // --target-balance <btc>: mine up to this balance
+    pub state_file: Option<String>, // --state-file <path>: reuse addresses across runs
+    pub demo_maturity: bool, // --demo-maturity: show an immature spend rejected
+    pub checkpoints: Vec<u64>, // --checkpoints <n,...>: depths to report the send at
+    pub keypool_size: Option<usize>, // --keypool-size <n>: keys to pre-generate in new wallets
+    pub out: Option<String>, // --out <path>: where --format protobuf/csv writes to
+    pub tags: BTreeMap<String, String>, // --tag <key=value>: metadata for the report, repeatable
+    pub wait_for_node: Option<Duration>, // --wait-for-node <secs>: wait this long for the node first
+}
+
+// The plain `cargo run` behaviour: the full flow on regtest with the 20 BTC payment
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            command: Command::Run,
+            format: Format::Text,
+            max_fee: Amount::from_str_in(DEFAULT_MAX_FEE_BTC, Denomination::Bitcoin).unwrap(),
+            yes: false,
+            recipients_file: None,
+            min_confirmations: None,
+            field: None,
+            trader_blank: false,
+            trader_watch_only: false,
+            trader_multisig: None,
+            deterministic: false,
+            network: Network::Regtest,
+            cookie_file: None,
+            webhook: None,
+            fee_rate: None,
+            fee: None,
+            subtract_fee: false,
+            replaceable: true,
+            psbt: false,
+            comment: None,
+            comment_to: None,
+            append: false,
+            run_id: None,
+            estimate_mode: None,
+            report_cdd: false,
+            snapshot_dir: None,
+            metrics_out: None,
+            repeat: false,
+            loop_delay: Duration::from_secs(1),
+            rpc_url: RPC_URL.to_string(),
+            rpc_user: None,
+            rpc_password: None,
+            miner_wallet: "Miner".to_string(),
+            trader_wallet: "Trader".to_string(),
+            amount: Amount::from_btc(20.0).unwrap(),
+            recipients: 1,
+            output_path: "../out.txt".to_string(),
+            test_accept: false,
+            price: None,
+            trace_change: false,
+            confirmations: 1,
+            no_send: false,
+            target_balance: None,
+            state_file: None,
+            demo_maturity: false,
+            checkpoints: Vec::new(),
+            keypool_size: None,
+            out: None,
+            tags: BTreeMap::new(),
+            wait_for_node: None,
+        }
+    }
+}
+
+// Where bitcoind writes its auth cookie inside `datadir` for `network`
+pub fn cookie_path(datadir: &str, network: Network) -> String {
+    let subdir = match network {
+        Network::Bitcoin => "",
+        Network::Testnet => "testnet3",
+        Network::Signet => "signet",
+        _ => "regtest",
+    };
+    std::path::Path::new(datadir)
+        .join(subdir)
+        .join(".cookie")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Where bitcoind keeps its data when started without -datadir
+pub fn default_datadir() -> Option<String> {
+    let home = std::env::var("HOME").ok();
+    let path = if cfg!(target_os = "windows") {
+        std::path::Path::new(&std::env::var("APPDATA").ok()?).join("Bitcoin")
+    } else if cfg!(target_os = "macos") {
+        std::path::Path::new(&home?).join("Library/Application Support/Bitcoin")
+    } else {
+        std::path::Path::new(&home?).join(".bitcoin")
+    };
+    Some(path.to_string_lossy().into_owned())
+}
+
+// Credentials for the node, in order of preference:
+// - the auth cookie given with --cookie-file/--datadir, which has to be readable
+// - --rpc-user/--rpc-password
+// - the user/password from the node config
+// - the cookie of a node running from the default datadir, but only once the
+//   node turns the user/password down. A cookie left by a crashed bitcoind, or
+//   one of another node, must not take their place.
+pub fn rpc_auth(options: &Options) -> bitcoincore_rpc::Result<Auth> {
+    if let Some(path) = &options.cookie_file {
+        File::open(path).map_err(|e| {
+            bitcoincore_rpc::Error::ReturnedError(format!("Can't read cookie file {path}: {e}"))
+        })?;
+        return Ok(Auth::CookieFile(path.into()));
+    }
+    if let (Some(user), Some(password)) = (&options.rpc_user, &options.rpc_password) {
+        return Ok(Auth::UserPass(user.clone(), password.clone()));
+    }
+    let defaults = Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned());
+    let discovered = default_datadir()
+        .map(|datadir| cookie_path(&datadir, options.network))
+        .filter(|path| File::open(path).is_ok());
+    let Some(path) = discovered else {
+        return Ok(defaults);
+    };
+    let probe = rpc_client(&options.rpc_url, &defaults)?;
+    match rpc::BitcoinRpc::call::<u64>(&probe, "uptime", &[]) {
+        Err(e) if unauthorized(&e) => {
+            debug(&format!(
+                "Node rejected {RPC_USER}, using auth cookie {path}"
+            ));
+            Ok(Auth::CookieFile(path.into()))
+        }
+        _ => Ok(defaults),
+    }
+}
+
+// Whether the node answered `error`'s request with 401 Unauthorized
+fn unauthorized(error: &bitcoincore_rpc::Error) -> bool {
+    let bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Transport(e)) = error
+    else {
+        return false;
+    };
+    matches!(
+        e.downcast_ref::<simple_http::Error>(),
+        Some(simple_http::Error::HttpErrorCode(401))
+    )
+}
+
+// The message of an error returned by the node itself, as opposed to a
+// transport or parsing failure
+pub fn rpc_error_message(error: &bitcoincore_rpc::Error) -> Option<&str> {
+    match error {
+        bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::error::Error::Rpc(e)) => {
+            Some(&e.message)
+        }
+        _ => None,
+    }
+}
+
+// Set from the SIGINT handler so long-running loops can stop cleanly on Ctrl-C
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+pub fn install_interrupt_handler() {
+    extern "C" fn on_sigint(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_interrupt_handler() {}
+
+// Sleep for `duration`, waking up early if Ctrl-C was pressed
+pub fn sleep_unless_interrupted(duration: Duration) {
+    let step = Duration::from_millis(100);
+    let mut slept = Duration::ZERO;
+    while slept < duration && !INTERRUPTED.load(Ordering::SeqCst) {
+        std::thread::sleep(step.min(duration - slept));
+        slept += step;
+    }
+}
+
+// Number of RPC requests sent to the node so far, for --metrics-out
+pub static RPC_CALLS: AtomicU64 = AtomicU64::new(0);
+
+// How often a request that failed for a transient reason is sent again
+// (--rpc-retries), the delay before the first retry (--rpc-backoff <ms>), which
+// doubles with every attempt, and whether to randomize it (--no-rpc-jitter
+// turns that off)
+pub static RPC_RETRIES: AtomicU32 = AtomicU32::new(5);
+pub static RPC_BACKOFF_MS: AtomicU64 = AtomicU64::new(500);
+pub static RPC_JITTER: AtomicBool = AtomicBool::new(true);
+
+// Longest single wait between retries, however many attempts came before
+const RPC_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Calls that only read, so sending one again after the connection dropped
+// mid-request can't pay, mine or broadcast twice
+pub const READ_ONLY_CALLS: [&str; 28] = [
+    "analyzepsbt",
+    "decodepsbt",
+    "decoderawtransaction",
+    "deriveaddresses",
+    "estimatesmartfee",
+    "getaddressinfo",
+    "getbalance",
+    "getbalances",
+    "getblock",
+    "getblockchaininfo",
+    "getblockcount",
+    "getblockhash",
+    "getblockheader",
+    "getdescriptorinfo",
+    "getmempoolentry",
+    "getmempoolinfo",
+    "getnetworkinfo",
+    "getrawtransaction",
+    "getreceivedbyaddress",
+    "gettransaction",
+    "getwalletinfo",
+    "listdescriptors",
+    "listsinceblock",
+    "listtransactions",
+    "listwallets",
+    "testmempoolaccept",
+    "uptime",
+    "verifymessage",
+];
+
+// Failures a freshly started bitcoind produces and that go away by themselves:
+// nothing listening on the port yet, -28 while the block index is loading, and
+// a wallet that is still being loaded or rescanned.
+// A refused connection never reached the node, so any call can go again. A
+// reset or aborted one may have been carried out before it dropped, so only
+// `read_only` calls are retried after that.
+fn transient_transport_error(error: &bitcoincore_rpc::jsonrpc::Error, read_only: bool) -> bool {
+    let bitcoincore_rpc::jsonrpc::Error::Transport(e) = error else {
+        return false;
+    };
+    let Some(simple_http::Error::SocketError(e)) = e.downcast_ref::<simple_http::Error>() else {
+        return false;
+    };
+    match e.kind() {
+        ErrorKind::ConnectionRefused => true,
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => read_only,
+        _ => false,
+    }
+}
+
+fn transient_response(response: &Response) -> bool {
+    match &response.error {
+        Some(e) if e.code == -28 => true,
+        Some(e) if e.code == -4 => e.message.contains("loading") || e.message.contains("rescan"),
+        _ => false,
+    }
+}
+
+// Delay before retry number `attempt` (starting at 1): exponential, capped at
+// RPC_MAX_BACKOFF, and with jitter somewhere between half and all of that
+fn retry_backoff(attempt: u32) -> Duration {
+    let base = Duration::from_millis(RPC_BACKOFF_MS.load(Ordering::Relaxed));
+    let backoff = base
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(RPC_MAX_BACKOFF);
+    if !RPC_JITTER.load(Ordering::Relaxed) {
+        return backoff;
+    }
+    // Good enough randomness for spreading out retries, without a rand dependency
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    backoff.mul_f64(0.5 + f64::from(nanos % 1000) / 2000.0)
+}
+
+// Send with `send` until it succeeds, fails for good or runs out of retries.
+// `read_only` says whether everything sent is in READ_ONLY_CALLS.
+fn with_retries<T>(
+    what: &str,
+    read_only: bool,
+    transient: impl Fn(&T) -> bool,
+    send: impl Fn() -> Result<T, bitcoincore_rpc::jsonrpc::Error>,
+) -> Result<T, bitcoincore_rpc::jsonrpc::Error> {
+    let retries = RPC_RETRIES.load(Ordering::Relaxed);
+    let mut attempt = 0;
+    loop {
+        let result = send();
+        let retry = match &result {
+            Ok(value) => transient(value),
+            Err(e) => transient_transport_error(e, read_only),
+        };
+        if !retry || attempt >= retries || INTERRUPTED.load(Ordering::SeqCst) {
+            return result;
+        }
+        attempt += 1;
+        let backoff = retry_backoff(attempt);
+        debug(&format!(
+            "{what} failed transiently, retry {attempt}/{retries} in {backoff:?}"
+        ));
+        sleep_unless_interrupted(backoff);
+    }
+}
+
+// How long to wait for the node to answer a request (--rpc-timeout <secs>),
+// and for the calls in SLOW_CALLS (--rpc-slow-timeout <secs>). The former is
+// what the HTTP transport uses by default.
+pub static RPC_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(15);
+pub static RPC_SLOW_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(300);
+
+// Calls that can legitimately take minutes: mining many blocks at once, and
+// the ones that rescan the chain
+pub const SLOW_CALLS: [&str; 6] = [
+    "generatetoaddress",
+    "rescanblockchain",
+    "importdescriptors",
+    "scantxoutset",
+    "loadwallet",
+    "createwallet",
+];
+
+// The plain HTTP transport `Client::new` would use, counting every request and
+// retrying the ones that failed because the node isn't ready yet. SLOW_CALLS
+// go through a second transport with the longer timeout.
+struct CountingTransport {
+    regular: SimpleHttpTransport,
+    slow: SimpleHttpTransport,
+}
+
+impl CountingTransport {
+    fn for_method(&self, method: &str) -> &SimpleHttpTransport {
+        if SLOW_CALLS.contains(&method) {
+            &self.slow
+        } else {
+            &self.regular
+        }
+    }
+}
+
+impl Transport for CountingTransport {
+    fn send_request(&self, request: Request) -> Result<Response, bitcoincore_rpc::jsonrpc::Error> {
+        let transport = self.for_method(request.method);
+        let read_only = READ_ONLY_CALLS.contains(&request.method);
+        with_retries(request.method, read_only, transient_response, || {
+            RPC_CALLS.fetch_add(1, Ordering::Relaxed);
+            transport.send_request(request.clone())
+        })
+    }
+
+    fn send_batch(
+        &self,
+        requests: &[Request],
+    ) -> Result<Vec<Response>, bitcoincore_rpc::jsonrpc::Error> {
+        let what = format!("Batch of {} requests", requests.len());
+        let transient = |responses: &Vec<Response>| responses.iter().any(transient_response);
+        let slow = requests.iter().any(|r| SLOW_CALLS.contains(&r.method));
+        let transport = if slow { &self.slow } else { &self.regular };
+        let read_only = requests.iter().all(|r| READ_ONLY_CALLS.contains(&r.method));
+        with_retries(&what, read_only, transient, || {
+            RPC_CALLS.fetch_add(requests.len() as u64, Ordering::Relaxed);
+            transport.send_batch(requests)
+        })
+    }
+
+    fn fmt_target(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.regular.fmt_target(f)
+    }
+}
+
+// Like `Client::new`, but the requests show up in the RPC_CALLS counter
+pub fn rpc_client(url: &str, auth: &Auth) -> bitcoincore_rpc::Result<Client> {
+    let (user, pass) = auth.clone().get_user_pass()?;
+    let mut builder = SimpleHttpTransport::builder()
+        .url(url)
+        .map_err(|e| bitcoincore_rpc::Error::JsonRpc(e.into()))?;
+    if let Some(user) = user {
+        builder = builder.auth(user, pass);
+    }
+    let secs = |timeout: &AtomicU64| Duration::from_secs(timeout.load(Ordering::Relaxed));
+    let transport = CountingTransport {
+        regular: builder.clone().timeout(secs(&RPC_TIMEOUT_SECS)).build(),
+        slow: builder.timeout(secs(&RPC_SLOW_TIMEOUT_SECS)).build(),
+    };
+    Ok(Client::from_jsonrpc(
+        bitcoincore_rpc::jsonrpc::Client::with_transport(transport),
+    ))
+}
+
+// RPC client scoped to one of the node's wallets
+pub fn wallet_client(url: &str, wallet_name: &str, auth: &Auth) -> bitcoincore_rpc::Result<Client> {
+    rpc_client(&format!("{url}/wallet/{wallet_name}"), auth)
+}
+
+// How long `wait-for-node` waits when --wait-for-node doesn't say
+pub const NODE_WAIT_DEFAULT: Duration = Duration::from_secs(60);
+const NODE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Why the node can't serve the flow yet, or None once it can: it answers both
+// getblockchaininfo and getnetworkinfo (neither does during -28 warmup) and
+// has loaded the blocks for all the headers it knows
+fn node_not_ready(rpc: &impl rpc::BitcoinRpc) -> Option<String> {
+    let info = match rpc.get_blockchain_info() {
+        Ok(info) => info,
+        Err(e) => return Some(e.to_string()),
+    };
+    if let Err(e) = rpc.get_network_info() {
+        return Some(e.to_string());
+    }
+    (info.blocks < info.headers).then(|| {
+        format!(
+            "loading blocks, {} of {} headers",
+            info.blocks, info.headers
+        )
+    })
+}
+
+// Poll the node until it is ready or `deadline` has passed. The transport's
+// own retries are switched off meanwhile, this loop does the waiting.
+pub fn wait_for_node(
+    rpc: &impl rpc::BitcoinRpc,
+    deadline: Duration,
+) -> bitcoincore_rpc::Result<()> {
+    let started = std::time::Instant::now();
+    let retries = RPC_RETRIES.swap(0, Ordering::Relaxed);
+    let mut last_reason = None;
+    let result = loop {
+        let Some(reason) = node_not_ready(rpc) else {
+            status!("Node ready after {}s", started.elapsed().as_secs());
+            break Ok(());
+        };
+        if started.elapsed() >= deadline || INTERRUPTED.load(Ordering::SeqCst) {
+            break Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "Node not ready after {}s: {reason}",
+                started.elapsed().as_secs()
+            )));
+        }
+        if last_reason.as_ref() != Some(&reason) {
+            status!(
+                "Waiting for node ({}s): {reason}",
+                started.elapsed().as_secs()
+            );
+            last_reason = Some(reason);
+        }
+        sleep_unless_interrupted(NODE_POLL_INTERVAL);
+    };
+    RPC_RETRIES.store(retries, Ordering::Relaxed);
+    result
+}
@@ -1,123 +1,1142 @@
-#![allow(unused)]
-use bitcoin::hex::DisplayHex;
-use bitcoincore_rpc::bitcoin::{Amount, SignedAmount};
-use bitcoincore_rpc::{Auth, Client, RawTx, RpcApi};
-use serde::Deserialize;
-use serde_json::json;
+use bitcoincore_rpc::bitcoin::hashes::{sha256, Hash};
+use bitcoincore_rpc::bitcoin::{Address, Amount, Network, Txid};
+use bitcoincore_rpc::json::EstimateMode;
+use bitcoincore_rpc::RpcApi;
+use capstone::mining::*;
+use capstone::psbt::*;
+use capstone::report::*;
+use capstone::transaction::*;
+use capstone::wallet::*;
+use capstone::*;
 use std::fs::File;
 use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-// Node access params
-const RPC_URL: &str = "http://127.0.0.1:18443"; // Default regtest RPC port
-const RPC_USER: &str = "alice";
-const RPC_PASS: &str = "password";
-
-// You can use calls not provided in RPC lib API using the generic `call` function.
-// An example of using the `send` RPC call, which doesn't have exposed API.
-// You can also use serde_json `Deserialize` derivation to capture the returned json result.
-fn send(rpc: &Client, addr: &str) -> bitcoincore_rpc::Result<String> {
-    let args = [
-        json!([{addr : 100 }]), // recipient address
-        json!(null),            // conf target
-        json!(null),            // estimate mode
-        json!(null),            // fee rate in sats/vb
-        json!(null),            // Empty option object
-    ];
-
-    #[derive(Deserialize)]
-    struct SendResult {
-        complete: bool,
-        txid: String,
-    }
-    let send_result = rpc.call::<SendResult>("send", &args)?;
-    assert!(send_result.complete);
-    Ok(send_result.txid)
+fn parse_network(name: &str) -> bitcoincore_rpc::Result<Network> {
+    name.parse().map_err(|_| {
+        bitcoincore_rpc::Error::ReturnedError(format!(
+            "Unknown network: {name}. Valid networks: regtest, testnet, signet, bitcoin"
+        ))
+    })
+}
+
+fn flag_not_valid(flag: &str, subcommand: &str) -> bitcoincore_rpc::Error {
+    bitcoincore_rpc::Error::ReturnedError(format!("{flag} is only valid for {subcommand}"))
+}
+
+// A short random-looking id for --run-id auto, unique enough to keep parallel
+// runs against one node apart
+fn generate_run_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let hash = sha256::Hash::hash(format!("{}-{nanos}", std::process::id()).as_bytes());
+    hash.to_string()[..12].to_string()
+}
+
+const USAGE: &str = "\
+Usage: rust [<command>] [options]
+
+Without a command (or with `send`) the Miner pays the Trader and the run is
+written to out.txt.
+
+Commands:
+  send                                  the full flow, the default
+  setup                                 create or load the wallets and stop
+  mine [--blocks <n>]                   mine to a fresh Miner address
+  report <txid>                         what the Miner wallet knows about a transaction
+  preview-addresses [--count <n>]       the next Miner addresses
+  heartbeat [--interval <secs>]         mine a block every interval until Ctrl-C
+  block <height|hash>                   show a block
+  mempool-info                          show the mempool
+  bumpfee <txid>                        replace an unconfirmed send with a higher fee
+  cpfp <txid>                           speed up a payment the Trader received
+  psbt create|sign|finalize|inspect [<file|base64>]
+  pay-many <file>                       pay every address in a JSON or CSV file
+  verify [--file <path>]                check an out.txt against the chain
+  wait-for-node                         return once the node is ready
+  scan-balance --descriptor <desc>      the UTXO set balance of a descriptor
+  sign-message --address <addr> --message <text>
+  verify-message --address <addr> --message <text> --signature <base64>
+  repl                                  an interactive console
+
+Connection:
+  --rpc-url <url>  --rpc-user <user>  --rpc-password <pass>  --cookie-file <path>
+  --datadir <path>  --network <name>  --config <path>  --wait-for-node <secs>
+  --rpc-retries <n>  --rpc-backoff <ms>  --no-rpc-jitter  --rpc-timeout <secs>
+  --rpc-slow-timeout <secs>
+
+Wallets:
+  --miner-wallet <name>  --trader-wallet <name>  --run-id <id|auto>
+  --trader-blank  --trader-watch-only  --trader-multisig <desc>
+  --keypool-size <n>  --deterministic  --state-file <path>
+
+Payment:
+  --amount <btc>  --recipients <n>  --recipients-file <path>  --max-fee <btc>
+  --yes  --fee-rate <sat/vB>  --fee <btc>  --estimate-mode <mode>
+  --subtract-fee  --no-rbf  --psbt  --comment <text>  --comment-to <text>
+  --test-accept  --no-send  --min-confirmations <n>  --target-balance <btc>
+  --confirmations <n>  --checkpoints <n,..>  --demo-maturity
+
+Output:
+  --format <text|json|csv|protobuf|summary>  --out <path>  --append  --field <name>
+  --tag <key=value>  --price <usd>  --report-cdd  --trace-change
+  --metrics-out <path>  --snapshot-dir <path>  --webhook <url>
+  --log-format <text|json>  --debug  --loop  --loop-delay <secs>
+
+  -h, --help                            print this help
+";
+
+fn parse_options() -> bitcoincore_rpc::Result<Options> {
+    let mut options = Options::default();
+    let mut datadir = None;
+    // The first of --amount/--recipients given, which --recipients-file replaces
+    let mut payment_flag = None;
+
+    // capstone.toml and CAPSTONE_* variables replace the defaults, flags replace those
+    let config_path = std::env::args().skip_while(|a| a != "--config").nth(1);
+    let config = config::Config::load(config_path.as_deref())?;
+    if let Some(url) = config.rpc_url {
+        options.rpc_url = normalize_rpc_url(&url)?;
+    }
+    if let Some(name) = config.network {
+        options.network = parse_network(&name)?;
+    }
+    options.rpc_user = config.rpc_user;
+    options.rpc_password = config.rpc_password;
+    if let Some(name) = config.miner_wallet {
+        options.miner_wallet = name;
+    }
+    if let Some(name) = config.trader_wallet {
+        options.trader_wallet = name;
+    }
+    if let Some(path) = config.output_path {
+        options.output_path = path;
+    }
+    if let Some(secs) = config.rpc_timeout {
+        RPC_TIMEOUT_SECS.store(secs, Ordering::Relaxed);
+    }
+    if let Some(secs) = config.rpc_slow_timeout {
+        RPC_SLOW_TIMEOUT_SECS.store(secs, Ordering::Relaxed);
+    }
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-fee" => options.max_fee = parse_amount("--max-fee", args.next())?,
+            "--yes" => options.yes = true,
+            "--recipients-file" => {
+                options.recipients_file = Some(required_value("--recipients-file", args.next())?)
+            }
+            "--min-confirmations" => {
+                options.min_confirmations = Some(parse_number("--min-confirmations", args.next())?)
+            }
+            "--field" => {
+                let name = required_value("--field", args.next())?;
+                if !Report::FIELDS.contains(&name.as_str()) {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                        "Unknown report field: {name}. Valid fields: {}",
+                        Report::FIELDS.join(", ")
+                    )));
+                }
+                STDOUT_RESERVED.store(true, Ordering::Relaxed);
+                options.field = Some(name);
+            }
+            "--format" => {
+                options.format = match required_value("--format", args.next())?.as_str() {
+                    "text" => Format::Text,
+                    "json" => {
+                        STDOUT_RESERVED.store(true, Ordering::Relaxed);
+                        Format::Json
+                    }
+                    "summary" => {
+                        STDOUT_RESERVED.store(true, Ordering::Relaxed);
+                        Format::Summary
+                    }
+                    "protobuf" => Format::Protobuf,
+                    "csv" => Format::Csv,
+                    other => {
+                        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                            "Unknown format: {other}. Valid formats: text, json, summary, \
+                             protobuf, csv"
+                        )))
+                    }
+                }
+            }
+            "--trader-blank" => options.trader_blank = true,
+            "--trader-watch-only" => options.trader_watch_only = true,
+            "--trader-multisig" => {
+                // The multisig keys live elsewhere, so the Trader only watches them
+                options.trader_multisig = Some(required_value("--trader-multisig", args.next())?);
+                options.trader_watch_only = true;
+                options.trader_blank = true;
+            }
+            "--config" => {
+                // Already loaded above
+                required_value("--config", args.next())?;
+            }
+            "--network" => {
+                options.network = parse_network(&required_value("--network", args.next())?)?
+            }
+            "--cookie-file" => {
+                options.cookie_file = Some(required_value("--cookie-file", args.next())?)
+            }
+            "--datadir" => datadir = Some(required_value("--datadir", args.next())?),
+            "--fee-rate" => {
+                let rate: f64 = parse_number("--fee-rate", args.next())?;
+                if rate <= 0.0 {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(
+                        "--fee-rate must be positive".to_string(),
+                    ));
+                }
+                options.fee_rate = Some(rate);
+            }
+            "--fee" => options.fee = Some(parse_amount("--fee", args.next())?),
+            "--subtract-fee" => options.subtract_fee = true,
+            "--no-rbf" => options.replaceable = false,
+            "--psbt" => options.psbt = true,
+            "--comment" => options.comment = Some(required_value("--comment", args.next())?),
+            "--comment-to" => {
+                options.comment_to = Some(required_value("--comment-to", args.next())?)
+            }
+            "--append" => options.append = true,
+            "--report-cdd" => options.report_cdd = true,
+            "--test-accept" => options.test_accept = true,
+            "--trace-change" => options.trace_change = true,
+            "--no-send" => options.no_send = true,
+            "--demo-maturity" => options.demo_maturity = true,
+            "--out" => options.out = Some(required_value("--out", args.next())?),
+            "--tag" => {
+                let tag = required_value("--tag", args.next())?;
+                let Some((key, value)) = tag.split_once('=').filter(|(key, _)| !key.is_empty())
+                else {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                        "Invalid --tag {tag}, expected key=value"
+                    )));
+                };
+                if options
+                    .tags
+                    .insert(key.to_string(), value.to_string())
+                    .is_some()
+                {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                        "Duplicate --tag {key}"
+                    )));
+                }
+            }
+            "--keypool-size" => {
+                options.keypool_size = Some(parse_number("--keypool-size", args.next())?)
+            }
+            "--checkpoints" => {
+                let list = required_value("--checkpoints", args.next())?;
+                let mut checkpoints = Vec::new();
+                for depth in list.split(',') {
+                    match depth.trim().parse::<u64>() {
+                        Ok(depth) if depth > 0 => checkpoints.push(depth),
+                        _ => {
+                            return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                                "Invalid confirmation depth in --checkpoints: {depth}"
+                            )))
+                        }
+                    }
+                }
+                checkpoints.sort_unstable();
+                checkpoints.dedup();
+                options.checkpoints = checkpoints;
+            }
+            "--state-file" => {
+                options.state_file = Some(required_value("--state-file", args.next())?)
+            }
+            "--target-balance" => {
+                options.target_balance = Some(parse_amount("--target-balance", args.next())?)
+            }
+            "--confirmations" => {
+                options.confirmations = parse_number("--confirmations", args.next())?;
+                if options.confirmations == 0 {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(
+                        "--confirmations must be at least 1".to_string(),
+                    ));
+                }
+            }
+            "--price" => {
+                let price: f64 = parse_number("--price", args.next())?;
+                if price <= 0.0 {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(
+                        "--price must be positive".to_string(),
+                    ));
+                }
+                options.price = Some(price);
+            }
+            "--rpc-url" => {
+                options.rpc_url = normalize_rpc_url(&required_value("--rpc-url", args.next())?)?
+            }
+            "--rpc-user" => options.rpc_user = Some(required_value("--rpc-user", args.next())?),
+            "--rpc-password" => {
+                options.rpc_password = Some(required_value("--rpc-password", args.next())?)
+            }
+            "--miner-wallet" => {
+                options.miner_wallet = required_value("--miner-wallet", args.next())?
+            }
+            "--trader-wallet" => {
+                options.trader_wallet = required_value("--trader-wallet", args.next())?
+            }
+            "--amount" => {
+                payment_flag.get_or_insert("--amount");
+                options.amount = parse_amount("--amount", args.next())?;
+                if options.amount == Amount::ZERO {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(
+                        "--amount must be positive".to_string(),
+                    ));
+                }
+            }
+            "--recipients" => {
+                payment_flag.get_or_insert("--recipients");
+                options.recipients = parse_number("--recipients", args.next())?;
+                if options.recipients == 0 {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(
+                        "--recipients must be at least 1".to_string(),
+                    ));
+                }
+            }
+            "--loop" => options.repeat = true,
+            "--loop-delay" => {
+                options.loop_delay = Duration::from_secs(parse_number("--loop-delay", args.next())?)
+            }
+            "--metrics-out" => {
+                options.metrics_out = Some(required_value("--metrics-out", args.next())?)
+            }
+            "--snapshot-dir" => {
+                options.snapshot_dir = Some(required_value("--snapshot-dir", args.next())?)
+            }
+            "--estimate-mode" => {
+                let mode = required_value("--estimate-mode", args.next())?;
+                options.estimate_mode = Some(match mode.to_lowercase().as_str() {
+                    "unset" => EstimateMode::Unset,
+                    "economical" => EstimateMode::Economical,
+                    "conservative" => EstimateMode::Conservative,
+                    _ => {
+                        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                            "Unknown estimate mode {mode}, expected unset, economical or \
+                             conservative"
+                        )))
+                    }
+                });
+            }
+            "--run-id" => {
+                let id = required_value("--run-id", args.next())?;
+                options.run_id = Some(if id == "auto" { generate_run_id() } else { id });
+            }
+            "--webhook" => options.webhook = Some(required_value("--webhook", args.next())?),
+            "--deterministic" => options.deterministic = true,
+            "--debug" => DEBUG.store(true, Ordering::Relaxed),
+            "--log-format" => match required_value("--log-format", args.next())?.as_str() {
+                "text" => LOG_JSON.store(false, Ordering::Relaxed),
+                "json" => LOG_JSON.store(true, Ordering::Relaxed),
+                other => {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                        "Unknown log format {other}, expected text or json"
+                    )))
+                }
+            },
+            "--rpc-retries" => RPC_RETRIES.store(
+                parse_number("--rpc-retries", args.next())?,
+                Ordering::Relaxed,
+            ),
+            "--rpc-backoff" => RPC_BACKOFF_MS.store(
+                parse_number("--rpc-backoff", args.next())?,
+                Ordering::Relaxed,
+            ),
+            "--no-rpc-jitter" => RPC_JITTER.store(false, Ordering::Relaxed),
+            "--rpc-timeout" => RPC_TIMEOUT_SECS.store(
+                parse_number("--rpc-timeout", args.next())?,
+                Ordering::Relaxed,
+            ),
+            "--rpc-slow-timeout" => RPC_SLOW_TIMEOUT_SECS.store(
+                parse_number("--rpc-slow-timeout", args.next())?,
+                Ordering::Relaxed,
+            ),
+            "preview-addresses" => options.command = Command::PreviewAddresses { count: 10 },
+            "mempool-info" => options.command = Command::MempoolInfo,
+            "wait-for-node" => options.command = Command::WaitForNode,
+            "verify" => options.command = Command::Verify { path: None },
+            "--file" => match &mut options.command {
+                Command::Verify { path } => *path = Some(required_value("--file", args.next())?),
+                _ => return Err(flag_not_valid("--file", "verify")),
+            },
+            "--wait-for-node" => {
+                options.wait_for_node = Some(Duration::from_secs(parse_number(
+                    "--wait-for-node",
+                    args.next(),
+                )?))
+            }
+            "send" => options.command = Command::Run,
+            "setup" => options.command = Command::Setup,
+            "mine" => options.command = Command::Mine { blocks: 1 },
+            "--blocks" => match &mut options.command {
+                Command::Mine { blocks } => *blocks = parse_number("--blocks", args.next())?,
+                _ => return Err(flag_not_valid("--blocks", "mine")),
+            },
+            "report" => {
+                options.command = Command::Report {
+                    txid: required_value("report", args.next())?,
+                }
+            }
+            "repl" => options.command = Command::Repl,
+            "bumpfee" => {
+                options.command = Command::BumpFee {
+                    txid: required_value("bumpfee", args.next())?,
+                }
+            }
+            "cpfp" => {
+                options.command = Command::Cpfp {
+                    txid: required_value("cpfp", args.next())?,
+                }
+            }
+            "psbt" => {
+                let action = required_value("psbt", args.next())?;
+                let input = match action.as_str() {
+                    // The PSBT is printed for piping into the next step
+                    "create" => {
+                        STDOUT_RESERVED.store(true, Ordering::Relaxed);
+                        None
+                    }
+                    "sign" => {
+                        STDOUT_RESERVED.store(true, Ordering::Relaxed);
+                        Some(required_value("psbt sign", args.next())?)
+                    }
+                    "finalize" => Some(required_value("psbt finalize", args.next())?),
+                    "inspect" => Some(required_value("psbt inspect", args.next())?),
+                    other => {
+                        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                            "Unknown psbt action: {other}. Valid actions: create, sign, \
+                             finalize, inspect"
+                        )))
+                    }
+                };
+                options.command = Command::Psbt { action, input };
+            }
+            "pay-many" => {
+                options.command = Command::PayMany {
+                    path: required_value("pay-many", args.next())?,
+                }
+            }
+            "scan-balance" => {
+                options.command = Command::ScanBalance {
+                    descriptor: String::new(),
+                }
+            }
+            "--descriptor" => match &mut options.command {
+                Command::ScanBalance { descriptor } => {
+                    *descriptor = required_value("--descriptor", args.next())?
+                }
+                _ => return Err(flag_not_valid("--descriptor", "scan-balance")),
+            },
+            "heartbeat" => options.command = Command::Heartbeat { interval: 10 },
+            "block" => {
+                options.command = Command::Block {
+                    id: required_value("block", args.next())?,
+                }
+            }
+            "--interval" => match &mut options.command {
+                Command::Heartbeat { interval } => {
+                    *interval = parse_number("--interval", args.next())?
+                }
+                _ => return Err(flag_not_valid("--interval", "heartbeat")),
+            },
+            "--count" => match &mut options.command {
+                Command::PreviewAddresses { count } => {
+                    *count = parse_number("--count", args.next())?
+                }
+                _ => return Err(flag_not_valid("--count", "preview-addresses")),
+            },
+            "sign-message" => {
+                options.command = Command::SignMessage {
+                    address: String::new(),
+                    message: String::new(),
+                }
+            }
+            "verify-message" => {
+                options.command = Command::VerifyMessage {
+                    address: String::new(),
+                    message: String::new(),
+                    signature: String::new(),
+                }
+            }
+            "--address" => match &mut options.command {
+                Command::SignMessage { address, .. } | Command::VerifyMessage { address, .. } => {
+                    *address = required_value("--address", args.next())?
+                }
+                _ => return Err(flag_not_valid("--address", "sign-message/verify-message")),
+            },
+            "--message" => match &mut options.command {
+                Command::SignMessage { message, .. } | Command::VerifyMessage { message, .. } => {
+                    *message = required_value("--message", args.next())?
+                }
+                _ => return Err(flag_not_valid("--message", "sign-message/verify-message")),
+            },
+            "--signature" => match &mut options.command {
+                Command::VerifyMessage { signature, .. } => {
+                    *signature = required_value("--signature", args.next())?
+                }
+                _ => return Err(flag_not_valid("--signature", "verify-message")),
+            },
+            "--help" | "-h" => {
+                print!("{USAGE}");
+                std::process::exit(0);
+            }
+            other => {
+                return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                    "Unknown argument: {other}"
+                )))
+            }
+        }
+    }
+
+    // Binary output never goes to the terminal, and CSV rows pile up in a file
+    if options.format == Format::Protobuf && options.out.is_none() {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--format protobuf requires --out <path>".to_string(),
+        ));
+    }
+    if options.format == Format::Csv && options.out.is_none() {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--format csv requires --out <path>".to_string(),
+        ));
+    }
+
+    // `report <txid>` prints what the wallet knows about any transaction, while
+    // the formats are written from the report of a run
+    if matches!(options.command, Command::Report { .. }) && options.format != Format::Text {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--format can't be used with report, run without a command to get a formatted report"
+                .to_string(),
+        ));
+    }
+
+    // Mine deep enough to reach the last checkpoint
+    if let Some(&deepest) = options.checkpoints.last() {
+        options.confirmations = options.confirmations.max(deepest);
+    }
+
+    // The payees are either --recipients Trader addresses paid --amount each or
+    // the list in --recipients-file
+    if let (Some(flag), Some(_)) = (payment_flag, &options.recipients_file) {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "{flag} and --recipients-file are mutually exclusive"
+        )));
+    }
+
+    // A fee is either a rate or an absolute amount, not both
+    if options.fee.is_some() && options.fee_rate.is_some() {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--fee and --fee-rate are mutually exclusive".to_string(),
+        ));
+    }
+
+    // --fee is settled through the change output, which --subtract-fee leaves alone
+    if options.fee.is_some() && options.subtract_fee {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--fee can't be combined with --subtract-fee".to_string(),
+        ));
+    }
+    // walletcreatefundedpsbt only takes fee rates
+    if options.fee.is_some() && options.psbt {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--fee can't be combined with --psbt, use --fee-rate".to_string(),
+        ));
+    }
+
+    // The node refuses an estimate mode next to an explicit fee rate
+    if options.fee_rate.is_some()
+        && matches!(
+            options.estimate_mode,
+            Some(EstimateMode::Economical | EstimateMode::Conservative)
+        )
+    {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--estimate-mode can't be combined with --fee-rate".to_string(),
+        ));
+    }
+
+    // Both halves of the credentials come from the same place
+    if options.rpc_user.is_some() != options.rpc_password.is_some() {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--rpc-user and --rpc-password must be given together".to_string(),
+        ));
+    }
+    if options.rpc_user.is_some() && (options.cookie_file.is_some() || datadir.is_some()) {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--rpc-user can't be combined with cookie authentication".to_string(),
+        ));
+    }
+    if options.miner_wallet == options.trader_wallet {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "--miner-wallet and --trader-wallet must differ".to_string(),
+        ));
+    }
+
+    // Only now do we know which network's subdirectory to look in
+    if let Some(datadir) = datadir {
+        if options.cookie_file.is_some() {
+            return Err(bitcoincore_rpc::Error::ReturnedError(
+                "--datadir and --cookie-file are mutually exclusive".to_string(),
+            ));
+        }
+        options.cookie_file = Some(cookie_path(&datadir, options.network));
+    }
+    Ok(options)
+}
+
+// The node endpoint with its scheme spelled out and without a trailing slash
+// or `/wallet/<name>` path, which wallet clients append themselves. A bare
+// host or host:port means http. Only plain http is spoken: the HTTP
+// transport has no TLS, so an https URL is refused here rather than failing
+// obscurely on the first call.
+fn normalize_rpc_url(url: &str) -> bitcoincore_rpc::Result<String> {
+    if let Some((scheme, _)) = url.split_once("://") {
+        if scheme.eq_ignore_ascii_case("https") {
+            return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "{url}: https is not supported, the RPC client only speaks plain http. \
+                 Reach the node through an SSH tunnel or a local TLS-terminating proxy"
+            )));
+        }
+        if !scheme.eq_ignore_ascii_case("http") {
+            return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "{url}: unsupported scheme {scheme}, expected http"
+            )));
+        }
+    }
+    let url = url.trim_end_matches('/');
+    let url = if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("http://{url}")
+    };
+    Ok(match url.find("/wallet/") {
+        Some(index) => {
+            status!(
+                "Warning: ignoring wallet path {} in --rpc-url, wallets are selected by name",
+                &url[index..]
+            );
+            url[..index].to_string()
+        }
+        None => url.to_string(),
+    })
+}
+
+// An interactive console against the node for the `repl` subcommand. Each line
+// is one command; a failing command prints its error and the prompt returns.
+// Ends on `quit` or EOF.
+fn repl(
+    rpc: &impl rpc::BitcoinRpc,
+    miner: &impl rpc::BitcoinRpc,
+    trader: &impl rpc::BitcoinRpc,
+    reward_address: &Address,
+    options: &Options,
+) -> bitcoincore_rpc::Result<()> {
+    status!("Commands: mine <n>, send <btc>, balance, mempool, report <txid>, quit");
+    let stdin = std::io::stdin();
+    loop {
+        print!("capstone> ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => Ok(()),
+            ["quit" | "exit"] => return Ok(()),
+            ["mine", n] => parse_number("mine", Some(n.to_string())).and_then(|n| {
+                let hashes = mine_blocks(miner, n, reward_address)?;
+                status!(
+                    "Mined {} blocks, tip {}",
+                    hashes.len(),
+                    rpc.get_block_count()?
+                );
+                Ok(())
+            }),
+            ["send", amount] => parse_amount("send", Some(amount.to_string())).and_then(|amount| {
+                let address = trader
+                    .get_new_address(Some("Received"), None)?
+                    .require_network(options.network)
+                    .map_err(|e| bitcoincore_rpc::Error::ReturnedError(e.to_string()))?;
+                let txid = send_payment(miner, &[(address.clone(), amount)], options)?;
+                status!("Sent {} BTC to {address}: {txid}", amount.to_btc());
+                Ok(())
+            }),
+            ["balance"] => {
+                print_balances("Miner", miner).and_then(|_| print_balances("Trader", trader))
+            }
+            ["mempool"] => show_mempool_info(rpc, &options.format),
+            ["report", txid] => txid
+                .parse::<Txid>()
+                .map_err(|e| bitcoincore_rpc::Error::ReturnedError(format!("Invalid txid: {e}")))
+                .and_then(|txid| print_wallet_transaction(miner, &txid)),
+            _ => Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "Unknown command: {}",
+                line.trim()
+            ))),
+        };
+        if let Err(e) = result {
+            status!("Error: {e}");
+        }
+    }
 }
 
 fn main() -> bitcoincore_rpc::Result<()> {
+    let options = parse_options().inspect_err(|_| eprint!("{USAGE}"))?;
+    if !options.repeat {
+        return run(&options);
+    }
+
+    // Soak test: repeat the whole flow with the same wallets until Ctrl-C
+    install_interrupt_handler();
+    let (mut succeeded, mut failed) = (0u64, 0u64);
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        match run(&options) {
+            Ok(()) => succeeded += 1,
+            Err(e) => {
+                failed += 1;
+                status!("Iteration {} failed: {e}", succeeded + failed);
+            }
+        }
+        status!("Iterations so far: {succeeded} succeeded, {failed} failed");
+        sleep_unless_interrupted(options.loop_delay);
+    }
+    status!("Interrupted after {} iterations", succeeded + failed);
+    status!("Succeeded: {succeeded}, failed: {failed}");
+    Ok(())
+}
+
+// How the Miner wallet is created when it doesn't exist yet
+fn miner_setup(options: &Options) -> WalletSetup {
+    WalletSetup {
+        watch_only: false,
+        blank: options.deterministic,
+        keypool_size: options.keypool_size,
+        deterministic: options.deterministic,
+    }
+}
+
+// How the Trader wallet is created when it doesn't exist yet
+fn trader_setup(options: &Options) -> WalletSetup {
+    WalletSetup {
+        watch_only: options.trader_watch_only,
+        blank: options.trader_blank || options.deterministic,
+        keypool_size: options.keypool_size,
+        // A watch-only Trader gets its keys from --trader-multisig instead
+        deterministic: options.deterministic && !options.trader_watch_only,
+    }
+}
+
+// Creates or loads the wallet `name` and connects to it
+fn open_wallet(
+    rpc: &impl rpc::BitcoinRpc,
+    auth: &bitcoincore_rpc::Auth,
+    name: &str,
+    setup: &WalletSetup,
+    options: &Options,
+) -> bitcoincore_rpc::Result<bitcoincore_rpc::Client> {
+    ensure_wallet(rpc, &options.rpc_url, auth, name, setup)?;
+    wallet_client(&options.rpc_url, name, auth)
+}
+
+// A fresh Miner address for block rewards
+fn new_reward_address(
+    miner: &impl rpc::BitcoinRpc,
+    network: Network,
+) -> bitcoincore_rpc::Result<Address> {
+    miner
+        .get_new_address(
+            Some("Mining Reward"),
+            Some(bitcoincore_rpc::json::AddressType::Bech32),
+        )?
+        .require_network(network)
+        .map_err(|e| {
+            bitcoincore_rpc::Error::ReturnedError(format!("Failed to create reward address: {e}"))
+        })
+}
+
+fn parse_txid(txid: &str) -> bitcoincore_rpc::Result<Txid> {
+    txid.parse()
+        .map_err(|e| bitcoincore_rpc::Error::ReturnedError(format!("Invalid txid {txid}: {e}")))
+}
+
+// The subcommands that work on existing wallets. Each creates or loads only the
+// wallet it needs, and none of them touches --state-file: blocks they mine pay
+// a fresh Miner address.
+fn run_command(
+    rpc: &impl rpc::BitcoinRpc,
+    auth: &bitcoincore_rpc::Auth,
+    miner_wallet_name: &str,
+    trader_wallet_name: &str,
+    options: &Options,
+) -> bitcoincore_rpc::Result<()> {
+    let miner = || open_wallet(rpc, auth, miner_wallet_name, &miner_setup(options), options);
+    let trader = || {
+        open_wallet(
+            rpc,
+            auth,
+            trader_wallet_name,
+            &trader_setup(options),
+            options,
+        )
+    };
+
+    match &options.command {
+        Command::SignMessage { address, message } => {
+            // Prove control of one of the Miner's addresses
+            status!("Signature: {}", sign_message(&miner()?, address, message)?);
+            Ok(())
+        }
+        Command::PreviewAddresses { count } => {
+            // Preview the addresses the Miner wallet will hand out next
+            for (i, address) in preview_addresses(&miner()?, *count)?.iter().enumerate() {
+                status!("Next address {i}: {address}");
+            }
+            Ok(())
+        }
+        Command::Heartbeat { interval } => {
+            let miner_client = miner()?;
+            let address = new_reward_address(&miner_client, options.network)?;
+            heartbeat(&miner_client, &address, *interval)
+        }
+        Command::Mine { blocks } => {
+            let miner_client = miner()?;
+            let address = new_reward_address(&miner_client, options.network)?;
+            mine_blocks(&miner_client, *blocks, &address)?;
+            status!("Mined {blocks} blocks to {address}");
+            print_balances(miner_wallet_name, &miner_client)
+        }
+        Command::Report { txid } => print_wallet_transaction(&miner()?, &parse_txid(txid)?),
+        Command::BumpFee { txid } => {
+            let bumped = bump_fee(&miner()?, &parse_txid(txid)?, options.fee_rate)?;
+            for error in &bumped.errors {
+                status!("Warning: {error}");
+            }
+            status!(
+                "Fee raised from {} BTC to {} BTC",
+                bumped.origfee.to_btc(),
+                bumped.fee.to_btc()
+            );
+            match (bumped.txid, bumped.psbt) {
+                (Some(replacement), _) => {
+                    status!("Replacement transaction ID: {replacement}");
+                    print_fee_rates(&rpc.get_mempool_entry(&replacement)?);
+                }
+                (None, Some(psbt)) => status!("Replacement needs signing elsewhere, PSBT: {psbt}"),
+                (None, None) => status!("The wallet returned no replacement"),
+            }
+            Ok(())
+        }
+        Command::Cpfp { txid } => {
+            let rate = options.fee_rate.unwrap_or(CPFP_DEFAULT_FEE_RATE);
+            let child = cpfp(&trader()?, &parse_txid(txid)?, rate)?;
+            status!("Child transaction ID: {child}");
+            print_fee_rates(&rpc.get_mempool_entry(&child)?);
+            Ok(())
+        }
+        Command::Psbt { action, input } => {
+            let input = input.as_deref().map(read_psbt).transpose()?;
+            match (action.as_str(), input) {
+                ("sign", Some(psbt)) => {
+                    let signed = sign_psbt(&miner()?, &psbt)?;
+                    status!("PSBT signed, complete: {}", signed.complete);
+                    export_psbt(&signed.psbt, options.out.as_deref())?;
+                }
+                ("finalize", Some(psbt)) => {
+                    let (_, raw) = finalize_psbt(rpc, &psbt)?;
+                    let txid = broadcast_checked(rpc, &raw)?;
+                    status!("Transaction ID: {txid}");
+                }
+                _ => {
+                    // create: an unsigned payment of --amount to a fresh Trader address
+                    let miner_client = miner()?;
+                    let address = trader()?
+                        .get_new_address(Some("Received"), None)?
+                        .require_network(options.network)
+                        .map_err(|e| {
+                            bitcoincore_rpc::Error::ReturnedError(format!(
+                                "Failed to create trader address: {e}"
+                            ))
+                        })?;
+                    mine_to_maturity(
+                        &miner_client,
+                        &new_reward_address(&miner_client, options.network)?,
+                        options.amount + options.max_fee,
+                        options.min_confirmations,
+                    )?;
+                    let created =
+                        create_psbt(&miner_client, &[(address.clone(), options.amount)], options)?;
+                    status!(
+                        "PSBT paying {} BTC to {address}, fee {} BTC",
+                        options.amount.to_btc(),
+                        created.fee.to_btc()
+                    );
+                    export_psbt(&created.psbt, options.out.as_deref())?;
+                }
+            }
+            Ok(())
+        }
+        Command::PayMany { path } => {
+            let recipients = load_recipients(path, options.network)?;
+            status!("Loaded {} recipients from {path}", recipients.len());
+            let total: Amount = recipients.iter().map(|(_, amount)| *amount).sum();
+            let miner_client = miner()?;
+            mine_to_maturity(
+                &miner_client,
+                &new_reward_address(&miner_client, options.network)?,
+                total + options.max_fee,
+                options.min_confirmations,
+            )?;
+            let txid = pay_many(&miner_client, &recipients, options)?;
+            print_fee_rates(&rpc.get_mempool_entry(&txid)?);
+            Ok(())
+        }
+        Command::Repl => {
+            let miner_client = miner()?;
+            let address = new_reward_address(&miner_client, options.network)?;
+            repl(rpc, &miner_client, &trader()?, &address, options)
+        }
+        // run() handles the flow, setup and the subcommands needing no wallet
+        _ => Ok(()),
+    }
+}
+
+// One pass of the flow (or the subcommand given on the command line)
+fn run(options: &Options) -> bitcoincore_rpc::Result<()> {
+    let started = Instant::now();
+    enter_phase("startup");
+
     // Connect to Bitcoin Core RPC
-    let rpc = Client::new(
-        RPC_URL,
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
+    let auth = rpc_auth(options)?;
+    let rpc = rpc_client(&options.rpc_url, &auth)?;
+
+    // In CI the node may still be starting up next to us
+    if let Command::WaitForNode = options.command {
+        return wait_for_node(&rpc, options.wait_for_node.unwrap_or(NODE_WAIT_DEFAULT));
+    }
+    if let Some(deadline) = options.wait_for_node {
+        wait_for_node(&rpc, deadline)?;
+    }
 
     // Get blockchain info
     let blockchain_info = rpc.get_blockchain_info()?;
-    println!("Blockchain Info: {blockchain_info:?}");
+    status!("Blockchain Info: {blockchain_info:?}");
+
+    if let Command::Block { id } = &options.command {
+        return show_block(&rpc, id);
+    }
+    if let Command::MempoolInfo = options.command {
+        return show_mempool_info(&rpc, &options.format);
+    }
+    if let Command::Verify { path } = &options.command {
+        let path = path.as_deref().unwrap_or(&options.output_path);
+        let mismatches = verify_out_txt(&rpc, &std::fs::read_to_string(path)?)?;
+        for m in &mismatches {
+            status!(
+                "Line {} ({}): {path} has {}, the chain has {}",
+                m.line,
+                m.field,
+                m.out_txt,
+                m.chain
+            );
+        }
+        if !mismatches.is_empty() {
+            return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "{path} disagrees with the chain on {} line(s)",
+                mismatches.len()
+            )));
+        }
+        status!("{path} matches the chain");
+        return Ok(());
+    }
+    if let Command::Psbt {
+        action,
+        input: Some(input),
+    } = &options.command
+    {
+        if action == "inspect" {
+            // Needs the node only, not the wallets
+            return inspect_psbt(&rpc, &read_psbt(input)?, options.network);
+        }
+    }
+    if let Command::ScanBalance { descriptor } = &options.command {
+        return scan_balance(&rpc, descriptor);
+    }
+    if let Command::VerifyMessage {
+        address,
+        message,
+        signature,
+    } = &options.command
+    {
+        let valid = verify_message(&rpc, address, message, signature)?;
+        status!("Signature valid: {valid}");
+        return Ok(());
+    }
 
     // ___________________________________________________________________________________
     // Create/Load the wallets, named 'Miner' and 'Trader'. Have logic to optionally
     // create/load them if they do not exist or not loaded already.
     // ___________________________________________________________________________________
 
-    let miner_wallet_name = "Miner";
-    let trader_wallet_name = "Trader";
+    enter_phase("wallet setup");
 
-    // Ensure Miner wallet is loaded
-    if !rpc.list_wallets()?.contains(&miner_wallet_name.to_string()) {
-        match rpc.load_wallet(miner_wallet_name) {
-            Ok(_) => println!("Loaded existing Miner wallet"),
-            Err(_) => {
-                match rpc.create_wallet(miner_wallet_name, None, None, None, None) {
-                    Ok(_) => println!("Created new Miner wallet"),
-                    Err(_) => {
-                        // Try loading again - wallet exists but wasn't loaded
-                        rpc.load_wallet(miner_wallet_name)?;
-                        println!("Loaded existing Miner wallet on retry");
-                    }
-                }
-            }
-        }
+    // Parallel runs against the same node each get their own pair of wallets
+    let (miner_wallet_name, trader_wallet_name) = match &options.run_id {
+        Some(id) => (
+            format!("{}-{id}", options.miner_wallet),
+            format!("{}-{id}", options.trader_wallet),
+        ),
+        None => (options.miner_wallet.clone(), options.trader_wallet.clone()),
+    };
+    let miner_wallet_name = miner_wallet_name.as_str();
+    let trader_wallet_name = trader_wallet_name.as_str();
+    status!("Using wallets {miner_wallet_name} and {trader_wallet_name}");
+
+    // The other subcommands work on the wallets as they are, see run_command
+    if !matches!(options.command, Command::Run | Command::Setup) {
+        return run_command(&rpc, &auth, miner_wallet_name, trader_wallet_name, options);
     }
 
-    // Ensure Trader wallet is loaded
-    if !rpc
-        .list_wallets()?
-        .contains(&trader_wallet_name.to_string())
-    {
-        match rpc.load_wallet(trader_wallet_name) {
-            Ok(_) => println!("Loaded existing Trader wallet"),
-            Err(_) => match rpc.create_wallet(trader_wallet_name, None, None, None, None) {
-                Ok(_) => println!("Created new Trader wallet"),
-                Err(_) => {
-                    rpc.load_wallet(trader_wallet_name)?;
-                    println!("Loaded existing Trader wallet on retry");
-                }
-            },
+    // The payment we are going to make from Miner: --amount to each of
+    // --recipients Trader addresses, or to the list in --recipients-file
+    let send_amount = options.amount;
+    let listed_payees = match &options.recipients_file {
+        Some(path) => {
+            let recipients = load_recipients(path, options.network)?;
+            status!("Loaded {} recipients from {path}", recipients.len());
+            Some(recipients)
         }
+        None => None,
+    };
+    let send_total = match &listed_payees {
+        Some(recipients) => recipients.iter().map(|(_, amount)| *amount).sum(),
+        None => send_amount * options.recipients,
+    };
+
+    // Ensure Miner wallet is loaded
+    ensure_wallet(
+        &rpc,
+        &options.rpc_url,
+        &auth,
+        miner_wallet_name,
+        &miner_setup(options),
+    )?;
+
+    // Ensure Trader wallet is loaded, unless we won't send to it
+    if !options.no_send {
+        ensure_wallet(
+            &rpc,
+            &options.rpc_url,
+            &auth,
+            trader_wallet_name,
+            &trader_setup(options),
+        )?;
+    }
+
+    let trader_multisig = options
+        .trader_multisig
+        .as_ref()
+        .filter(|_| !options.no_send);
+    if let Some(descriptor) = trader_multisig {
+        import_active_descriptor(
+            &wallet_client(&options.rpc_url, trader_wallet_name, &auth)?,
+            descriptor,
+            false,
+        )?;
+        status!("Imported multisig receiving descriptor into Trader wallet");
     }
 
+    let trader_for_validation = if options.no_send {
+        None
+    } else {
+        Some(wallet_client(&options.rpc_url, trader_wallet_name, &auth)?)
+    };
+    validate_wallets(
+        &wallet_client(&options.rpc_url, miner_wallet_name, &auth)?,
+        trader_for_validation.as_ref(),
+        options,
+    )?;
+
     // ___________________________________________________________________________________
     // Generate spendable balances in the Miner wallet. How many blocks needs to be mined?
     // ___________________________________________________________________________________
 
+    enter_phase("mining");
+
     // Switch to Miner wallet context
-    let miner_client = Client::new(
-        &format!("{RPC_URL}/wallet/{miner_wallet_name}"),
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
+    let miner_client = wallet_client(&options.rpc_url, miner_wallet_name, &auth)?;
 
-    // Generate one address from the Miner wallet with label "Mining Reward"
-    let miner_address = miner_client.get_new_address(
-        Some("Mining Reward"),
-        Some(bitcoincore_rpc::json::AddressType::Bech32),
-    )?;
+    // Generate one address from the Miner wallet with label "Mining Reward", or
+    // reuse the one from --state-file
+    let saved_state = options
+        .state_file
+        .as_deref()
+        .and_then(|path| load_state(path, miner_wallet_name, trader_wallet_name));
+    let saved_miner_address = reuse_address(
+        &miner_client,
+        saved_state.as_ref().map(|s| &s.miner_address),
+        miner_wallet_name,
+        options.network,
+    );
+    let miner_address = match saved_miner_address {
+        Some(address) => address,
+        None => miner_client.get_new_address(
+            Some("Mining Reward"),
+            Some(bitcoincore_rpc::json::AddressType::Bech32),
+        )?,
+    };
+    let mut state = RunState {
+        miner_wallet: miner_wallet_name.to_string(),
+        trader_wallet: trader_wallet_name.to_string(),
+        miner_address: miner_address.clone(),
+        trader_address: saved_state.and_then(|s| s.trader_address),
+    };
+    if let Some(path) = &options.state_file {
+        save_state(path, &state)?;
+    }
     let mining_reward_address = miner_address
         .clone()
-        .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)
+        .require_network(options.network)
         .map_err(|e| {
             bitcoincore_rpc::Error::ReturnedError(format!("Failed to create miner address: {e}"))
         })?;
 
-    println!("Miner address (Mining Reward): {mining_reward_address}");
+    status!("Miner address (Mining Reward): {mining_reward_address}");
+    ensure_owned(&miner_client, &mining_reward_address, miner_wallet_name)?;
+
+    if let Command::Setup = options.command {
+        status!("Wallets {miner_wallet_name} and {trader_wallet_name} are ready");
+        return Ok(());
+    }
 
     // Mine new blocks to this address until you get positive wallet balance
-    // In regtest, coinbase rewards mature after 100 blocks, so we need to mine 101 blocks
-    // to have spendable balance from the first block
-    let blocks_to_generate = 101;
-    let block_hashes =
-        miner_client.generate_to_address(blocks_to_generate, &mining_reward_address)?;
-    println!("Generated {blocks_to_generate} blocks to miner address");
+    // In regtest, coinbase rewards mature after 100 blocks, so on a fresh chain we
+    // need to mine 101 blocks to have spendable balance from the first block.
+    // Only mine what the send (plus the largest fee we are willing to pay) still
+    // requires: nothing on a re-run, and only the remainder when an earlier run
+    // was killed halfway through mining, since its immature rewards count too.
+    // --target-balance mines towards a larger balance, never a smaller one.
+    let mut required = send_total + options.max_fee;
+    if let Some(target) = options.target_balance {
+        required = required.max(target);
+        status!("Mining to a spendable balance of {} BTC", required.to_btc());
+    }
+    let blocks_mined = mine_to_maturity(
+        &miner_client,
+        &mining_reward_address,
+        required,
+        options.min_confirmations,
+    )?;
+    if blocks_mined > 0 {
+        status!("Generated {blocks_mined} blocks to miner address");
+    } else {
+        status!("Miner balance already covers the send, skipping mining");
+    }
 
     // Comment: Wallet balance for block rewards behaves this way because in Bitcoin,
     // coinbase transactions (block rewards) have a maturity period of 100 blocks in regtest mode.
@@ -126,217 +1145,455 @@ fn main() -> bitcoincore_rpc::Result<()> {
     // blockchain reorganizations that could make spent coinbase outputs invalid.
 
     // Print the balance of the Miner wallet
-    let miner_balance = miner_client.get_balance(None, None)?;
-    println!("Miner wallet balance: {} BTC", miner_balance.to_btc());
+    let miner_balance = miner_client.get_balance(options.min_confirmations, None)?;
+    match options.min_confirmations {
+        Some(minconf) => status!(
+            "Miner wallet balance ({minconf}+ confirmations): {} BTC",
+            miner_balance.to_btc()
+        ),
+        None => status!("Miner wallet balance: {} BTC", miner_balance.to_btc()),
+    }
+
+    // Break out the block rewards that are not spendable yet
+    let immature = immature_coinbase(&miner_client)?;
+    let immature_total: Amount = immature.iter().map(|(amount, _)| *amount).sum();
+    status!(
+        "Immature coinbase: {} BTC in {} outputs",
+        immature_total.to_btc(),
+        immature.len()
+    );
+    for (amount, matures_at) in &immature {
+        status!("  {} BTC matures at height {matures_at}", amount.to_btc());
+    }
+
+    if options.demo_maturity {
+        demo_maturity(&miner_client, &mining_reward_address)?;
+    }
+
+    if options.no_send {
+        // Funding only: report what mining produced and stop
+        let report = MiningReport {
+            miner_wallet: miner_wallet_name.to_string(),
+            miner_address: mining_reward_address.clone(),
+            blocks_mined,
+            block_height: rpc.get_block_count()?,
+            balance: miner_balance,
+            immature: immature_total,
+        };
+        status!(
+            "Skipping the send (--no-send); {} holds {} BTC at height {}",
+            report.miner_wallet,
+            report.balance.to_btc(),
+            report.block_height
+        );
+        if options.format == Format::Json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        return Ok(());
+    }
 
     // ___________________________________________________________________________________
     // Load Trader wallet and generate a new address
     // ___________________________________________________________________________________
 
     // Switch to Trader wallet context
-    let trader_client = Client::new(
-        &format!("{RPC_URL}/wallet/{trader_wallet_name}"),
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
+    let trader_client = wallet_client(&options.rpc_url, trader_wallet_name, &auth)?;
 
     // Create a receiving address labeled "Received" from Trader wallet
-    let trader_address = trader_client.get_new_address(
-        Some("Received"),
-        Some(bitcoincore_rpc::json::AddressType::Bech32),
-    )?;
+    let trader_address_type = options.trader_multisig.as_deref().map_or(
+        bitcoincore_rpc::json::AddressType::Bech32,
+        descriptor_address_type,
+    );
+    let saved_trader_address = reuse_address(
+        &trader_client,
+        state.trader_address.as_ref(),
+        trader_wallet_name,
+        options.network,
+    );
+    let trader_address = match saved_trader_address {
+        Some(address) => address,
+        None => trader_client.get_new_address(Some("Received"), Some(trader_address_type))?,
+    };
+    if let Some(path) = &options.state_file {
+        state.trader_address = Some(trader_address.clone());
+        save_state(path, &state)?;
+    }
     let trader_receive_address = trader_address
         .clone()
-        .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)
+        .require_network(options.network)
         .map_err(|e| {
             bitcoincore_rpc::Error::ReturnedError(format!("Failed to create trader address: {e}"))
         })?;
-    println!("Trader address (Received): {trader_receive_address}");
+    status!("Trader address (Received): {trader_receive_address}");
+    ensure_owned(&trader_client, &trader_receive_address, trader_wallet_name)?;
+
+    // If both wallets share keys every output would look like the payment and
+    // change detection could never find the change
+    if mining_reward_address == trader_receive_address {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Miner and Trader resolved to the same address {trader_receive_address}; \
+             do the two wallets share a descriptor?"
+        )));
+    }
+
+    // With --recipients, fresh Trader addresses for the other payments
+    let mut payees = match listed_payees {
+        Some(recipients) => recipients,
+        None => vec![(trader_receive_address.clone(), send_amount)],
+    };
+    for _ in 1..options.recipients {
+        let address = trader_client
+            .get_new_address(Some("Received"), Some(trader_address_type))?
+            .require_network(options.network)
+            .map_err(|e| {
+                bitcoincore_rpc::Error::ReturnedError(format!(
+                    "Failed to create trader address: {e}"
+                ))
+            })?;
+        status!("Trader address (Received): {address}");
+        payees.push((address, send_amount));
+    }
 
     // ___________________________________________________________________________________
-    // Send 20 BTC from Miner to Trader
+    // Pay --amount to each of the --recipients Trader addresses, or pay the
+    // --recipients-file list
     // ___________________________________________________________________________________
 
-    // Send a transaction paying 20 BTC from Miner wallet to Trader's wallet
-    let send_amount = Amount::from_btc(20.0).unwrap();
-    let txid = miner_client.send_to_address(
-        &trader_receive_address,
-        send_amount,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-    )?;
-    println!("Transaction ID: {txid}");
+    enter_phase("send");
+
+    // One transaction from the Miner wallet paying every address in `payees`
+
+    if let Some(dir) = &options.snapshot_dir {
+        write_snapshot(&rpc, &miner_client, &trader_client, dir, "before")?;
+    }
+
+    // The node may have been restarted while we were mining
+    let miner_client =
+        refresh_wallet_client(&options.rpc_url, miner_client, miner_wallet_name, &auth)?;
+    let trader_client =
+        refresh_wallet_client(&options.rpc_url, trader_client, trader_wallet_name, &auth)?;
+
+    // Check the fee the wallet would pay before broadcasting anything
+    if let Some(rate) = options.fee_rate {
+        check_relay_minimum(&rpc, rate)?;
+    }
+    let expected_fee = match options.fee {
+        Some(fee) => fee,
+        None => preview_send_fee(&miner_client, &payees, options)?,
+    };
+    status!("Expected fee: {} BTC", expected_fee.to_btc());
+    verify_fee_within_limit(expected_fee, options.max_fee, options.yes)?;
+
+    if options.test_accept {
+        let result = test_accept_payment(&miner_client, &payees, options)?;
+        if result.allowed {
+            status!(
+                "Mempool would accept the send: {} vbytes, fee {} BTC",
+                result
+                    .vsize
+                    .map_or("unknown".to_string(), |v| v.to_string()),
+                result
+                    .fees
+                    .map_or("unknown".to_string(), |f| f.base.to_btc().to_string())
+            );
+        } else {
+            let reason = MempoolRejection::from_reason(&result.reject_reason.unwrap_or_default());
+            status!("Mempool would reject the send: {reason}");
+            if !options.yes {
+                return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                    "testmempoolaccept rejected the send ({reason}); re-run with --yes to send anyway"
+                )));
+            }
+        }
+    }
+
+    // What the Trader addresses held before, to measure the payments against
+    // later. A --recipients-file address may belong to some other wallet.
+    let mut received_before = Vec::new();
+    for (address, _) in &payees {
+        let before = if address_ownership(&trader_client, address)?.is_mine {
+            Some(trader_client.get_received_by_address(address, None)?)
+        } else {
+            None
+        };
+        received_before.push(before);
+    }
+
+    // With --psbt the send goes through the PSBT steps, each recorded for the report
+    let mut psbt_stages = Vec::new();
+    let send = |stages: &mut Vec<PsbtStage>| {
+        if options.psbt {
+            send_with_psbt(&miner_client, &payees, options, stages)
+        } else {
+            send_payment(&miner_client, &payees, options)
+        }
+    };
+    let txid = match send(&mut psbt_stages) {
+        Ok(txid) => txid,
+        Err(e) if needs_confirmed_funds(&miner_client, &e, send_total)? => {
+            // The coins are there but too fresh to spend, give them a confirmation
+            status!("Funds are not confirmed yet, mining 1 block and retrying the send");
+            mine_blocks(&rpc, 1, &mining_reward_address)?;
+            psbt_stages.clear();
+            send(&mut psbt_stages)?
+        }
+        Err(e) => return Err(e),
+    };
+    status!("Transaction ID: {txid}");
 
     // ___________________________________________________________________________________
     // Check transaction in mempool
     // ___________________________________________________________________________________
 
     // Fetch the unconfirmed transaction from the node's mempool
-    let mempool_entry = rpc.get_mempool_entry(&txid)?;
-    println!("Mempool entry: {mempool_entry:?}");
+    let mempool_entry = match verified_mempool_entry(&rpc, &txid) {
+        Ok(entry) => entry,
+        Err(e) => {
+            // Explain a transaction that was evicted by a replacement
+            if let TxStatus::Replaced { by } = tx_status(&rpc, &miner_client, &txid)? {
+                let by = by.map_or("an unknown transaction".to_string(), |t| t.to_string());
+                return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                    "Transaction {txid} was likely replaced by {by}"
+                )));
+            }
+            return Err(e);
+        }
+    };
+    status!("Mempool entry: {mempool_entry:?}");
+    print_fee_rates(&mempool_entry);
+    if let Some(requested) = options.fee_rate {
+        let actual = effective_fee_rates(&mempool_entry).individual;
+        assert_fee_rate(actual, requested, FEE_RATE_TOLERANCE);
+    }
 
     // ____________________________________________________________________________________
     // Mine 1 block to confirm the transaction
     // ____________________________________________________________________________________
 
-    // Confirm the transaction by mining 1 block
-    let confirmation_block = rpc.generate_to_address(1, &mining_reward_address)?;
-    let block_hash = confirmation_block[0];
-    println!("Transaction confirmed in block: {block_hash}");
+    // Confirm the transaction by mining 1 block, or --confirmations blocks. Each
+    // block pays its reward to a fresh Miner address to avoid address reuse.
+    // With --checkpoints the transaction is also captured at each listed depth.
+    let mut confirmation_blocks = Vec::new();
+    let mut reward_addresses = Vec::new();
+    let mut checkpoints = Vec::new();
+    for depth in 1..=options.confirmations {
+        let reward_address = new_reward_address(&miner_client, options.network)?;
+        confirmation_blocks.extend(mine_blocks(&rpc, 1, &reward_address)?);
+        reward_addresses.push(reward_address);
+        if options.checkpoints.contains(&depth) {
+            let checkpoint = checkpoint(&rpc, &miner_client, &trader_client, &txid)?;
+            status!(
+                "Checkpoint: {} confirmations at tip {}, Trader balance {} BTC",
+                checkpoint.confirmations,
+                checkpoint.tip_height,
+                checkpoint.trader_balance.to_btc()
+            );
+            checkpoints.push(checkpoint);
+        }
+    }
+    let block_hash = confirming_block(&rpc, &miner_client, &txid, confirmation_blocks[0])?;
+    status!("Transaction confirmed in block: {block_hash}");
+    if let Some(dir) = &options.snapshot_dir {
+        write_snapshot(&rpc, &miner_client, &trader_client, dir, "after")?;
+    }
 
     // ____________________________________________________________________________________
     // Extract all required transaction details
     // ____________________________________________________________________________________
 
-    // Get the raw transaction first
-    let miner_tx = miner_client.get_raw_transaction(&txid, None)?;
+    enter_phase("report");
 
-    // Miner's Change Address
-    let miner_raw_tx = miner_client.decode_raw_transaction(&miner_tx, Some(true))?;
+    let payment = Payment {
+        txid,
+        block_hash,
+        miner_address: mining_reward_address.clone(),
+        recipients: payees.clone(),
+        psbt_stages,
+        miner_wallet: miner_wallet_name.to_string(),
+        trader_wallet: trader_wallet_name.to_string(),
+        blocks_mined,
+        mempool_entry,
+        reward_addresses,
+        checkpoints,
+    };
+    let report = build_report(&rpc, &miner_client, payment, options)?;
 
-    println!("Transaction outputs:");
-    for (i, vout) in miner_raw_tx.vout.iter().enumerate() {
-        println!("Output {}: {:?}", i, vout.script_pub_key.address);
+    // Closing balances, after the send confirmed
+    print_balances(miner_wallet_name, &miner_client)?;
+    print_balances(trader_wallet_name, &trader_client)?;
+    // Measured against what the outputs hold, which is less than was asked
+    // for when --subtract-fee took the fee out of them
+    for (output, before) in report.recipient_outputs.iter().zip(received_before) {
+        if let Some(before) = before {
+            verify_received(&trader_client, &output.address, before, output.amount)?;
+        }
     }
-    println!("Trader address: {trader_receive_address}");
 
-    // Handle the case where there might be no change output
-    let miner_vout_option = miner_raw_tx.vout.iter().find(|v| {
-        if let Some(addr) = &v.script_pub_key.address {
-            addr != &trader_receive_address
+    // ____________________________________________________________________________________
+    // Write the data to ../out.txt in the specified format given in readme.md
+    // ____________________________________________________________________________________
+
+    let describe_vout = |vout: Option<u32>| vout.map_or("none".to_string(), |n| n.to_string());
+    status!("Trader output index: {}", describe_vout(report.trader_vout));
+    status!("Change output index: {}", describe_vout(report.change_vout));
+    status!("Inputs selected: {}", report.input_count);
+    if let Some(comment) = &report.comment {
+        status!("Comment: {comment}");
+    }
+    if let Some(comment_to) = &report.comment_to {
+        status!("Comment to: {comment_to}");
+    }
+    if report.input_count > 50 {
+        debug(&format!(
+            "The wallet consolidated {} inputs into this payment, which makes it larger and more \
+             expensive. On regtest this usually means many small UTXOs from repeated mining.",
+            report.input_count
+        ));
+    }
+    if let Some(output_type) = &report.trader_output_type {
+        if options.trader_multisig.is_some() {
+            status!("Trader output script: {output_type} (multisig)");
         } else {
-            false
+            status!("Trader output script: {output_type}");
         }
-    });
-
-    // Get the raw transaction
-    let raw_tx = miner_client.get_raw_transaction(&txid, None)?;
-    let decoded_tx = miner_client.decode_raw_transaction(&raw_tx, Some(true))?;
-
-    // Find change output by comparing against trader address
-    let trader_addr_str = trader_receive_address.to_string();
-
-    let mut change_address = mining_reward_address.clone(); // fallback
-    let mut change_amount = 0.0;
-
-    // Look through all outputs to find the change
-    for vout in &decoded_tx.vout {
-        if let Some(addr) = &vout.script_pub_key.address {
-            // Convert address to string for comparison
-            let output_addr = addr
-                .clone()
-                .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)
-                .map_err(|e| {
-                    bitcoincore_rpc::Error::ReturnedError(format!(
-                        "Failed to process output address: {e}"
-                    ))
-                })?;
-
-            let output_addr_str = output_addr.to_string();
-            println!(
-                "Checking output: {} BTC to {output_addr_str}",
-                vout.value.to_btc()
-            );
-
-            // If this output is NOT going to the trader, it's the change
-            if output_addr_str != trader_addr_str {
-                change_address = output_addr;
-                change_amount = vout.value.to_btc();
-                println!("Found change output: {change_amount} BTC to {change_address}");
-                break;
-            }
+    }
+    for source in &report.funding_sources {
+        match source.coinbase_height {
+            Some(height) => status!(
+                "Funded by {}:{} ({} BTC), mined in block {height}",
+                source.txid,
+                source.vout,
+                source.amount.to_btc()
+            ),
+            None => status!(
+                "Funded by {}:{} ({} BTC)",
+                source.txid,
+                source.vout,
+                source.amount.to_btc()
+            ),
         }
     }
+    if let (Some(fee_usd), Some(amount_usd)) = (report.fee_usd, report.amount_usd) {
+        status!("In USD: sent ${amount_usd:.2} paying ${fee_usd:.2} in fees");
+    }
+    if let Some(cdd) = report.coin_days_destroyed {
+        status!("Coin days destroyed: {cdd} (BTC x blocks of age)");
+    }
+    status!(
+        "Fees collected by the miner in block {}: {} BTC",
+        report.block_height,
+        report.confirming_block_fees.to_btc()
+    );
 
-    // If no change was found, there might be an issue with the transaction
-    if change_amount == 0.0 {
-        println!("Warning: No change output found. This might indicate:");
-        println!("1. The input amount exactly equals output + fees");
-        println!("2. There's an issue with address comparison");
-        println!("3. The transaction structure is different than expected");
-
-        // Let's examine all outputs more carefully
-        println!("All transaction outputs:");
-        for (i, vout) in decoded_tx.vout.iter().enumerate() {
-            println!("  Output {}: {} BTC", i, vout.value.to_btc());
-            if let Some(addr) = &vout.script_pub_key.address {
-                let addr_str = addr
-                    .clone()
-                    .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)
-                    .map_err(|e| {
-                        bitcoincore_rpc::Error::ReturnedError(format!("Address error: {e}"))
-                    })?
-                    .to_string();
-                println!("    Address: {addr_str}");
-                println!("    Is trader address? {}", addr_str == trader_addr_str);
-            }
-        }
+    let mut report_addresses = vec![&report.miner_input_address, &report.trader_output_address];
+    report_addresses.extend(&report.change_address);
+    assert_network(&report_addresses, options.network)?;
+
+    // Format the data to the expected format
+    let block_time = rpc.get_block_header_info(&report.block_hash)?.time as u64;
+    let report = Report::new(report, block_time);
+    let output_content = report.out_txt();
+    status!("\nOutput content:\n{output_content}");
+
+    if options.append {
+        // Keep a history of runs, one JSON object per line, dated by finished_at
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&options.output_path)?;
+        writeln!(file, "{}", serde_json::to_string(&report)?)?;
+        status!(
+            "\nReport appended as a JSON line to {}",
+            options.output_path
+        );
+    } else {
+        let mut file = File::create(&options.output_path)?;
+        file.write_all(output_content.as_bytes())?;
+        status!("\nTransaction details written to {}", options.output_path);
     }
 
-    // Get transaction details using the miner client (since it sent the transaction)
-    let tx_details = miner_client.get_transaction(&txid, Some(true))?;
-    let raw_tx_info = rpc.get_raw_transaction_info(&txid, None)?;
-    let block_info = rpc.get_block(&block_hash)?;
-    let block_height = rpc.get_block_count()?;
-
-    // Extract input information
-    let input_amount = tx_details
-        .details
-        .iter()
-        .find(|d| d.category == bitcoincore_rpc::json::GetTransactionResultDetailCategory::Send)
-        .map(|d| d.amount.to_btc().abs())
-        .unwrap_or(0.0);
-
-    let output_amount = 20.0; // We sent 20 BTC
-    let fee = tx_details.fee.unwrap_or(SignedAmount::ZERO).to_btc().abs();
-
-    // Convert trader address to string for comparison
-    let trader_addr_str = trader_receive_address.to_string();
-
-    // Extract output info
-    let tx_details = miner_client.get_transaction(&txid, Some(true))?;
-    let tx = tx_details.transaction().unwrap(); // Fully decoded transaction
-                                                // let fee = tx_details
-                                                //     .fee
-                                                //     .unwrap_or(SignedAmount::from_btc(0.0).unwrap());
-    let fee = tx_details.fee.unwrap_or(SignedAmount::ZERO).to_btc().abs();
-
-    let outputs = &tx.output;
-    let mut trader_output = None;
-    let mut change_output = None;
-
-    for out in outputs {
-        let out_address = bitcoincore_rpc::bitcoin::Address::from_script(
-            &out.script_pubkey,
-            bitcoincore_rpc::bitcoin::Network::Regtest,
-        )
-        .unwrap();
-        if out_address == trader_address {
-            trader_output = Some((out_address, out.value));
-        } else {
-            change_output = Some((out_address, out.value));
+    // Push the report to a dashboard. This is best effort, out.txt is already written.
+    if let Some(url) = &options.webhook {
+        let body = serde_json::to_string(&report)?;
+        match post_json(url, &body) {
+            Ok(status) => status!("Webhook {url} responded with HTTP {status}"),
+            Err(e) => status!("Warning: failed to POST report to webhook {url}: {e}"),
         }
     }
 
-    println!("Looking for change address (trader address: {trader_addr_str})");
-    println!("Change address: {change_address}");
-
-    // ____________________________________________________________________________________
-    // Write the data to ../out.txt in the specified format given in readme.md
-    // ____________________________________________________________________________________
+    if let Some(path) = &options.metrics_out {
+        let metrics = report.metrics(RPC_CALLS.load(Ordering::Relaxed), started.elapsed());
+        std::fs::write(path, metrics)?;
+        status!("Metrics written to {path}");
+    }
 
-    // Format the data to the expected format
-    let output_content = format!(
-        "{txid}\n{mining_reward_address}\n{input_amount}\n{trader_receive_address}\n{output_amount}\n{change_address}\n{change_amount}\n{fee}\n{block_height}\n{block_hash}"
-    );
-    println!("\nOutput content:\n{output_content}");
+    if options.format == Format::Summary {
+        println!("{}", report.summary());
+    }
+    if options.format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+    if let (Format::Csv, Some(path)) = (&options.format, &options.out) {
+        append_csv(path, &report)?;
+        status!("CSV row appended to {path}");
+    }
+    if let (Format::Protobuf, Some(path)) = (&options.format, &options.out) {
+        std::fs::write(path, report.to_protobuf())?;
+        status!("Protobuf report written to {path}");
+    }
 
-    let mut file = File::create("../out.txt")?;
-    file.write_all(output_content.as_bytes())?;
-    println!("\nTransaction details written to out.txt");
+    if let Some(name) = &options.field {
+        // Undecorated, for `TXID=$(cargo run -- --field txid)`
+        println!("{}", report.field(name).unwrap_or_default());
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_rpc_url_adds_http_to_a_bare_host() {
+        assert_eq!(normalize_rpc_url("127.0.0.1").unwrap(), "http://127.0.0.1");
+    }
+
+    #[test]
+    fn normalize_rpc_url_keeps_host_and_port() {
+        assert_eq!(
+            normalize_rpc_url("localhost:18443").unwrap(),
+            "http://localhost:18443"
+        );
+        assert_eq!(
+            normalize_rpc_url("http://127.0.0.1:18443").unwrap(),
+            "http://127.0.0.1:18443"
+        );
+    }
+
+    #[test]
+    fn normalize_rpc_url_drops_a_trailing_slash() {
+        assert_eq!(
+            normalize_rpc_url("http://127.0.0.1:18443/").unwrap(),
+            "http://127.0.0.1:18443"
+        );
+    }
+
+    #[test]
+    fn normalize_rpc_url_strips_a_wallet_path() {
+        assert_eq!(
+            normalize_rpc_url("http://127.0.0.1:18443/wallet/Miner").unwrap(),
+            "http://127.0.0.1:18443"
+        );
+        assert_eq!(
+            normalize_rpc_url("http://127.0.0.1:18443/wallet/Miner/").unwrap(),
+            "http://127.0.0.1:18443"
+        );
+    }
+
+    #[test]
+    fn normalize_rpc_url_rejects_other_schemes() {
+        assert!(normalize_rpc_url("https://node.example:443").is_err());
+        assert!(normalize_rpc_url("ftp://127.0.0.1:18443").is_err());
+    }
+}
@@ -0,0 +1,321 @@
+// Mining blocks and reasoning about block rewards: how many blocks a balance
+// needs, when coinbase outputs mature, and inspecting the blocks themselves.
+
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, Txid};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::*;
+
+// Mine `count` blocks paying the block reward to `address`
+pub fn mine_blocks(
+    rpc: &impl rpc::BitcoinRpc,
+    count: u64,
+    address: &Address,
+) -> bitcoincore_rpc::Result<Vec<BlockHash>> {
+    rpc.generate_to_address(count, address)
+}
+
+pub fn hash_for_height(
+    rpc: &impl rpc::BitcoinRpc,
+    height: u64,
+) -> bitcoincore_rpc::Result<BlockHash> {
+    rpc.get_block_hash(height)
+}
+
+pub fn height_for_hash(
+    rpc: &impl rpc::BitcoinRpc,
+    hash: &BlockHash,
+) -> bitcoincore_rpc::Result<u64> {
+    Ok(rpc.get_block_header_info(hash)?.height as u64)
+}
+
+// `height_for_hash` for many blocks at once, in a single batch
+pub fn heights_for_hashes(
+    rpc: &impl rpc::BitcoinRpc,
+    hashes: &[BlockHash],
+) -> bitcoincore_rpc::Result<HashMap<BlockHash, u64>> {
+    let args: Vec<_> = hashes.iter().map(|hash| vec![json!(hash)]).collect();
+    let headers: Vec<bitcoincore_rpc::json::GetBlockHeaderResult> =
+        rpc.call_batch("getblockheader", &args)?;
+    Ok(headers
+        .into_iter()
+        .map(|header| (header.hash, header.height as u64))
+        .collect())
+}
+
+// Block arguments can be given as a height or a hash, resolve either to both
+pub fn resolve_block(
+    rpc: &impl rpc::BitcoinRpc,
+    id: &str,
+) -> bitcoincore_rpc::Result<(BlockHash, u64)> {
+    if let Ok(height) = id.parse::<u64>() {
+        return Ok((hash_for_height(rpc, height)?, height));
+    }
+    let hash = id.parse::<BlockHash>().map_err(|e| {
+        bitcoincore_rpc::Error::ReturnedError(format!("Not a block height or hash: {id} ({e})"))
+    })?;
+    Ok((hash, height_for_hash(rpc, &hash)?))
+}
+
+// Print a short summary of a block
+pub fn show_block(rpc: &impl rpc::BitcoinRpc, id: &str) -> bitcoincore_rpc::Result<()> {
+    let (hash, height) = resolve_block(rpc, id)?;
+    let header = rpc.get_block_header_info(&hash)?;
+    status!("Block {height}: {hash}");
+    status!("  Confirmations: {}", header.confirmations);
+    status!("  Time: {}", header.time);
+    status!("  Transactions: {}", header.n_tx);
+
+    // The stripped size leaves out witness data, which is what SegWit discounts
+    let info = rpc.get_block_info(&hash)?;
+    status!("  Size: {} bytes, weight: {} WU", info.size, info.weight);
+    if let Some(stripped) = info.strippedsize {
+        let witness = info.size.saturating_sub(stripped);
+        status!(
+            "  Stripped size: {stripped} bytes, witness data: {witness} bytes ({:.1}%)",
+            witness_percentage(info.size, stripped)
+        );
+    }
+    Ok(())
+}
+
+// Share of a block's serialized size taken up by witness data
+pub fn witness_percentage(size: usize, stripped_size: usize) -> f64 {
+    if size == 0 {
+        return 0.0;
+    }
+    size.saturating_sub(stripped_size) as f64 * 100.0 / size as f64
+}
+
+// Act as a block producer: mine one block every `interval` seconds until Ctrl-C
+pub fn heartbeat(
+    rpc: &impl rpc::BitcoinRpc,
+    address: &Address,
+    interval: u64,
+) -> bitcoincore_rpc::Result<()> {
+    install_interrupt_handler();
+    status!("Mining a block every {interval}s to {address}, press Ctrl-C to stop");
+
+    let mut mined = 0;
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        let block_hash = mine_blocks(rpc, 1, address)?[0];
+        let height = height_for_hash(rpc, &block_hash)?;
+        status!("Mined block {height}: {block_hash}");
+        mined += 1;
+        sleep_unless_interrupted(Duration::from_secs(interval));
+    }
+    status!("Stopped after mining {mined} blocks");
+    Ok(())
+}
+
+// Coinbase outputs need this many blocks on top before they can be spent
+pub const COINBASE_MATURITY: u32 = 100;
+
+// Block rewards that `get_balance` leaves out because they are not mature yet,
+// as (amount, height at which it becomes spendable). `listunspent` never returns
+// immature coinbase outputs, so we take them from the wallet's transaction list
+// where they are tagged with the "immature" category.
+pub fn immature_coinbase(
+    wallet: &impl rpc::BitcoinRpc,
+) -> bitcoincore_rpc::Result<Vec<(Amount, u32)>> {
+    let transactions = wallet.list_transactions(None, Some(9_999_999), None, None)?;
+    Ok(transactions
+        .iter()
+        .filter(|tx| {
+            tx.detail.category
+                == bitcoincore_rpc::json::GetTransactionResultDetailCategory::Immature
+        })
+        .filter_map(|tx| {
+            let height = tx.info.blockheight?;
+            Some((
+                tx.detail.amount.to_unsigned().ok()?,
+                height + COINBASE_MATURITY,
+            ))
+        })
+        .collect())
+}
+
+// Regtest halves the block subsidy every 150 blocks instead of 210,000
+pub const REGTEST_HALVING_INTERVAL: u64 = 150;
+
+pub fn block_subsidy(height: u64) -> Amount {
+    let halvings = height / REGTEST_HALVING_INTERVAL;
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from_sat(Amount::from_int_btc(50).to_sat() >> halvings)
+}
+
+// --demo-maturity: try spending a block reward before it matured and show the
+// node refusing it. The spend only goes through testmempoolaccept, so nothing
+// is broadcast either way.
+pub fn demo_maturity(
+    wallet: &impl rpc::BitcoinRpc,
+    address: &Address,
+) -> bitcoincore_rpc::Result<()> {
+    let transactions = wallet.list_transactions(None, Some(9_999_999), None, None)?;
+    let Some(coinbase) = transactions.iter().find(|tx| {
+        tx.detail.category == bitcoincore_rpc::json::GetTransactionResultDetailCategory::Immature
+    }) else {
+        status!("Maturity demo: no immature block reward to try spending");
+        return Ok(());
+    };
+    let amount = coinbase.detail.amount.to_unsigned().map_err(|e| {
+        bitcoincore_rpc::Error::ReturnedError(format!("Unexpected coinbase amount: {e}"))
+    })?;
+
+    let input = bitcoincore_rpc::json::CreateRawTransactionInput {
+        txid: coinbase.info.txid,
+        vout: coinbase.detail.vout,
+        sequence: None,
+    };
+    // Late in the halving schedule the reward doesn't cover even this fee
+    let Some(spend) = amount
+        .checked_sub(Amount::from_sat(10_000))
+        .filter(|spend| *spend > Amount::ZERO)
+    else {
+        status!(
+            "Maturity demo: the block reward of {} BTC is too small to spend",
+            amount.to_btc()
+        );
+        return Ok(());
+    };
+    let mut outputs = HashMap::new();
+    outputs.insert(address.to_string(), spend);
+    let unsigned = wallet.create_raw_transaction_hex(&[input], &outputs, None, None)?;
+    let signed = wallet.sign_raw_transaction_with_wallet(unsigned, None, None)?;
+    let result = wallet
+        .test_mempool_accept(&[&signed.hex])?
+        .pop()
+        .ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError(
+                "testmempoolaccept returned no result".to_string(),
+            )
+        })?;
+
+    if result.allowed {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Maturity demo: the node accepted a spend of immature coinbase {}",
+            coinbase.info.txid
+        )));
+    }
+    status!(
+        "Maturity demo: spending coinbase {} ({} confirmations) was rejected: {}",
+        coinbase.info.txid,
+        coinbase.info.confirmations,
+        result.reject_reason.unwrap_or_default()
+    );
+    Ok(())
+}
+
+// Fees collected by the miner of `block`: whatever its coinbase pays out on
+// top of the block subsidy
+pub fn block_fees(block: &bitcoincore_rpc::bitcoin::Block, height: u64) -> Amount {
+    let coinbase_total: Amount = block.txdata[0].output.iter().map(|out| out.value).sum();
+    coinbase_total
+        .checked_sub(block_subsidy(height))
+        .unwrap_or(Amount::ZERO)
+}
+
+// How many blocks need to be mined on top of `tip` until `deficit` more BTC is
+// spendable, counting both the currently immature rewards and the rewards of
+// the blocks we mine ourselves (which only help after 100 blocks).
+pub fn blocks_needed(deficit: Amount, immature: &[(Amount, u32)], tip: u64) -> u64 {
+    let mut matured = Amount::ZERO;
+    let mut blocks = 0;
+    while matured < deficit {
+        blocks += 1;
+        let height = tip + blocks;
+        matured += immature
+            .iter()
+            .filter(|(_, matures_at)| u64::from(*matures_at) == height)
+            .map(|(amount, _)| *amount)
+            .sum();
+        if blocks > u64::from(COINBASE_MATURITY) {
+            let subsidy = block_subsidy(height - u64::from(COINBASE_MATURITY));
+            if subsidy == Amount::ZERO {
+                // Subsidy has run out, mining more will never get us there
+                break;
+            }
+            matured += subsidy;
+        }
+    }
+    blocks
+}
+
+// Mine to `address` until `wallet` can spend `required`. Block rewards only
+// mature after 100 blocks, so on a fresh chain that takes 101 blocks, while
+// rewards still maturing from earlier runs shorten it. Returns how many blocks
+// were mined, none when the balance already covers `required`.
+pub fn mine_to_maturity(
+    wallet: &impl rpc::BitcoinRpc,
+    address: &Address,
+    required: Amount,
+    min_confirmations: Option<usize>,
+) -> bitcoincore_rpc::Result<u64> {
+    let balance = wallet.get_balance(min_confirmations, None)?;
+    if balance >= required {
+        return Ok(0);
+    }
+    let immature = immature_coinbase(wallet)?;
+    let blocks = blocks_needed(required - balance, &immature, wallet.get_block_count()?);
+    Ok(mine_blocks(wallet, blocks, address)?.len() as u64)
+}
+
+// The block confirming `txid`. Normally that is `mined`, the block we mined
+// for it, but on a node shared with other miners a reorg can orphan it
+// (confirmations -1); the wallet then knows where the transaction ended up.
+pub fn confirming_block(
+    rpc: &impl rpc::BitcoinRpc,
+    wallet: &impl rpc::BitcoinRpc,
+    txid: &Txid,
+    mined: BlockHash,
+) -> bitcoincore_rpc::Result<BlockHash> {
+    if rpc.get_block_header_info(&mined)?.confirmations >= 1 {
+        return Ok(mined);
+    }
+    status!("Warning: block {mined} is no longer in the active chain");
+    let tx = wallet.get_transaction(txid, None)?;
+    match tx.info.blockhash {
+        Some(hash) if tx.info.confirmations >= 1 => {
+            status!("Transaction {txid} is now confirmed in block {hash}");
+            Ok(hash)
+        }
+        _ => Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Transaction {txid} lost its confirmation in a reorg and is unconfirmed again"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_needed_is_zero_without_a_deficit() {
+        assert_eq!(blocks_needed(Amount::ZERO, &[], 0), 0);
+    }
+
+    #[test]
+    fn blocks_needed_waits_for_maturity_on_a_fresh_chain() {
+        assert_eq!(blocks_needed(Amount::from_int_btc(1), &[], 0), 101);
+        // Two rewards' worth takes a second block past maturity
+        assert_eq!(blocks_needed(Amount::from_int_btc(60), &[], 0), 102);
+    }
+
+    #[test]
+    fn blocks_needed_counts_rewards_already_maturing() {
+        let immature = [(Amount::from_int_btc(50), 5), (Amount::from_int_btc(50), 7)];
+        assert_eq!(blocks_needed(Amount::from_int_btc(50), &immature, 0), 5);
+        assert_eq!(blocks_needed(Amount::from_int_btc(100), &immature, 0), 7);
+    }
+
+    #[test]
+    fn blocks_needed_stops_once_the_subsidy_runs_out() {
+        let tip = 64 * REGTEST_HALVING_INTERVAL;
+        assert_eq!(blocks_needed(Amount::from_int_btc(1), &[], tip), 101);
+    }
+}
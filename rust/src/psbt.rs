@@ -0,0 +1,349 @@
+// Paying the Trader through a PSBT (BIP 174) instead of `sendtoaddress`: the
+// Miner wallet funds an unsigned PSBT, signs it in a separate step, the node
+// finalizes it into a network transaction and only that is broadcast. Each step
+// hands over a base64 PSBT, so the steps can also run one at a time with the
+// PSBT passed along in a file. `psbt inspect` shows what a PSBT holds at any
+// point of the way.
+
+use bitcoincore_rpc::bitcoin::amount::serde::as_btc;
+use bitcoincore_rpc::bitcoin::psbt::Psbt;
+use bitcoincore_rpc::bitcoin::{Address, Amount, Network, Script, Txid};
+use bitcoincore_rpc::json::{
+    FinalizePsbtResult, WalletCreateFundedPsbtResult, WalletProcessPsbtResult,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::transaction::*;
+use crate::*;
+
+// The PSBT as one step of the flow left it
+#[derive(Serialize, Clone)]
+pub struct PsbtStage {
+    // created, signed or finalized
+    pub stage: String,
+    // Base64, as the node hands it out
+    pub psbt: String,
+    // Whether every input carries what it needs to be spent
+    pub complete: bool,
+}
+
+// A funded, unsigned PSBT paying each of `payees`, funded the way a send with
+// `options` would be
+pub fn create_psbt(
+    wallet: &impl rpc::BitcoinRpc,
+    payees: &[(Address, Amount)],
+    options: &Options,
+) -> bitcoincore_rpc::Result<WalletCreateFundedPsbtResult> {
+    let outputs: serde_json::Map<String, serde_json::Value> = payees
+        .iter()
+        .map(|(address, amount)| (address.to_string(), json!(amount.to_btc())))
+        .collect();
+    let args = [
+        json!([]),                                     // inputs, the wallet picks
+        json!(outputs),                                // address -> amount
+        json!(0),                                      // locktime
+        json!(funding_options(payees.len(), options)), // fee and funding settings
+        json!(true),                                   // include BIP 32 derivation paths
+    ];
+    wallet.call("walletcreatefundedpsbt", &args)
+}
+
+// Add the wallet's signatures to `psbt`
+pub fn sign_psbt(
+    wallet: &impl rpc::BitcoinRpc,
+    psbt: &str,
+) -> bitcoincore_rpc::Result<WalletProcessPsbtResult> {
+    wallet.call("walletprocesspsbt", &[json!(psbt), json!(true)])
+}
+
+// Finalize a fully signed `psbt`: the finalized PSBT, and the network
+// transaction extracted from it
+pub fn finalize_psbt(
+    rpc: &impl rpc::BitcoinRpc,
+    psbt: &str,
+) -> bitcoincore_rpc::Result<(String, Vec<u8>)> {
+    let missing = || {
+        bitcoincore_rpc::Error::ReturnedError(
+            "The PSBT is missing signatures and can't be finalized".to_string(),
+        )
+    };
+    let finalized: FinalizePsbtResult = rpc.call("finalizepsbt", &[json!(psbt), json!(false)])?;
+    let finalized_psbt = finalized
+        .psbt
+        .filter(|_| finalized.complete)
+        .ok_or_else(missing)?;
+    let extracted: FinalizePsbtResult =
+        rpc.call("finalizepsbt", &[json!(finalized_psbt), json!(true)])?;
+    let raw = extracted.hex.ok_or_else(missing)?;
+    Ok((finalized_psbt, raw))
+}
+
+// A PSBT given on the command line: the contents of the file at `arg` if
+// there is one, otherwise `arg` itself as base64
+pub fn read_psbt(arg: &str) -> bitcoincore_rpc::Result<String> {
+    match std::fs::read_to_string(arg) {
+        Ok(contents) => Ok(contents.trim().to_string()),
+        Err(_) => Ok(arg.trim().to_string()),
+    }
+}
+
+// Write `psbt` to `path` when one is given, otherwise print it
+pub fn export_psbt(psbt: &str, path: Option<&str>) -> bitcoincore_rpc::Result<()> {
+    match path {
+        Some(path) => {
+            std::fs::write(path, format!("{psbt}\n"))?;
+            status!("PSBT written to {path}");
+        }
+        None => println!("{psbt}"),
+    }
+    Ok(())
+}
+
+// The whole flow for one payment, recording the PSBT after every step in
+// `stages`. The finalized transaction goes through testmempoolaccept before
+// it is broadcast.
+pub fn send_with_psbt(
+    wallet: &impl rpc::BitcoinRpc,
+    payees: &[(Address, Amount)],
+    options: &Options,
+    stages: &mut Vec<PsbtStage>,
+) -> bitcoincore_rpc::Result<Txid> {
+    let created = create_psbt(wallet, payees, options)?;
+    status!("PSBT created, fee {} BTC", created.fee.to_btc());
+    stages.push(PsbtStage {
+        stage: "created".to_string(),
+        psbt: created.psbt.clone(),
+        complete: false,
+    });
+
+    let signed = sign_psbt(wallet, &created.psbt)?;
+    status!("PSBT signed, complete: {}", signed.complete);
+    stages.push(PsbtStage {
+        stage: "signed".to_string(),
+        psbt: signed.psbt.clone(),
+        complete: signed.complete,
+    });
+
+    let (finalized, raw) = finalize_psbt(wallet, &signed.psbt)?;
+    status!("PSBT finalized, {} bytes to broadcast", raw.len());
+    stages.push(PsbtStage {
+        stage: "finalized".to_string(),
+        psbt: finalized,
+        complete: true,
+    });
+    Ok(broadcast_checked(wallet, &raw)?)
+}
+
+// What `analyzepsbt` says about a PSBT: which role has to act on it next, and
+// per input what is still missing
+#[derive(Deserialize)]
+struct PsbtAnalysis {
+    #[serde(default)]
+    inputs: Vec<InputAnalysis>,
+    next: String,
+}
+
+#[derive(Deserialize)]
+struct InputAnalysis {
+    is_final: bool,
+    missing: Option<MissingItems>,
+}
+
+#[derive(Deserialize)]
+struct MissingItems {
+    #[serde(default)]
+    pubkeys: Vec<String>,
+    #[serde(default)]
+    signatures: Vec<String>,
+    redeemscript: Option<String>,
+    witnessscript: Option<String>,
+}
+
+impl MissingItems {
+    fn describe(&self) -> String {
+        let mut items = Vec::new();
+        if !self.pubkeys.is_empty() {
+            items.push(format!("pubkeys of {}", self.pubkeys.join(" ")));
+        }
+        if !self.signatures.is_empty() {
+            items.push(format!("signatures of {}", self.signatures.join(" ")));
+        }
+        if let Some(hash) = &self.redeemscript {
+            items.push(format!("redeem script {hash}"));
+        }
+        if let Some(hash) = &self.witnessscript {
+            items.push(format!("witness script {hash}"));
+        }
+        items.join(", ")
+    }
+}
+
+// The part of `decodepsbt` we use: the fee, known once every input has its UTXO
+#[derive(Deserialize)]
+struct DecodedPsbt {
+    #[serde(default, with = "as_btc::opt")]
+    fee: Option<Amount>,
+}
+
+// Standard base64 with padding to bytes. The bitcoin crate here is built
+// without its base64 feature, and PSBTs travel in this form.
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.trim().trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+// psbt inspect: print a PSBT as tables of its inputs and outputs. The node
+// supplies the fee and what each input still lacks (decodepsbt, analyzepsbt);
+// signatures and key origins, taproot ones included, come from parsing the
+// PSBT here.
+pub fn inspect_psbt(
+    rpc: &impl rpc::BitcoinRpc,
+    psbt_base64: &str,
+    network: Network,
+) -> bitcoincore_rpc::Result<()> {
+    let bytes = base64_decode(psbt_base64).ok_or_else(|| {
+        bitcoincore_rpc::Error::ReturnedError("The PSBT is not valid base64".to_string())
+    })?;
+    let psbt = Psbt::deserialize(&bytes)
+        .map_err(|e| bitcoincore_rpc::Error::ReturnedError(format!("Can't parse the PSBT: {e}")))?;
+    let decoded: DecodedPsbt = rpc.call("decodepsbt", &[json!(psbt_base64)])?;
+    let analysis: PsbtAnalysis = rpc.call("analyzepsbt", &[json!(psbt_base64)])?;
+    let address = |script: &Script| {
+        Address::from_script(script, network).map_or("(no address)".to_string(), |a| a.to_string())
+    };
+
+    status!(
+        "PSBT of {}: {} inputs, {} outputs, fee {}, next step: {}",
+        psbt.unsigned_tx.txid(),
+        psbt.inputs.len(),
+        psbt.outputs.len(),
+        decoded
+            .fee
+            .map_or("unknown".to_string(), |fee| format!("{} BTC", fee.to_btc())),
+        analysis.next
+    );
+
+    status!("");
+    status!(
+        "{:<5} {:<68} {:>14} {:>5} {:<5} Missing",
+        "Input",
+        "Spends",
+        "Amount (BTC)",
+        "Sigs",
+        "Final"
+    );
+    for (i, (txin, input)) in psbt.unsigned_tx.input.iter().zip(&psbt.inputs).enumerate() {
+        let utxo = input.witness_utxo.clone().or_else(|| {
+            input
+                .non_witness_utxo
+                .as_ref()
+                .and_then(|tx| tx.output.get(txin.previous_output.vout as usize).cloned())
+        });
+        let signatures = input.partial_sigs.len()
+            + input.tap_script_sigs.len()
+            + usize::from(input.tap_key_sig.is_some());
+        let analyzed = analysis.inputs.get(i);
+        let missing = analyzed
+            .and_then(|a| a.missing.as_ref())
+            .map_or(String::new(), MissingItems::describe);
+        let missing = match utxo {
+            None => "UTXO".to_string(),
+            Some(_) => missing,
+        };
+        status!(
+            "{:<5} {:<68} {:>14} {:>5} {:<5} {missing}",
+            i,
+            txin.previous_output.to_string(),
+            utxo.as_ref()
+                .map_or("?".to_string(), |u| u.value.to_btc().to_string()),
+            signatures,
+            if analyzed.is_some_and(|a| a.is_final) {
+                "yes"
+            } else {
+                "no"
+            },
+        );
+        if let Some(utxo) = &utxo {
+            status!("      paying {}", address(&utxo.script_pubkey));
+        }
+        for pubkey in input.partial_sigs.keys() {
+            status!("      signed by {pubkey}");
+        }
+        if input.tap_key_sig.is_some() {
+            status!("      signed by the taproot key path");
+        }
+        for (pubkey, (fingerprint, path)) in &input.bip32_derivation {
+            status!("      key {pubkey} from [{fingerprint}] {path}");
+        }
+        for (pubkey, (_, (fingerprint, path))) in &input.tap_key_origins {
+            status!("      taproot key {pubkey} from [{fingerprint}] {path}");
+        }
+    }
+
+    status!("");
+    status!("{:<6} {:<64} {:>14}", "Output", "Address", "Amount (BTC)");
+    for (i, (txout, output)) in psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .zip(&psbt.outputs)
+        .enumerate()
+    {
+        status!(
+            "{:<6} {:<64} {:>14}",
+            i,
+            address(&txout.script_pubkey),
+            txout.value.to_btc()
+        );
+        for (pubkey, (fingerprint, path)) in &output.bip32_derivation {
+            status!("       key {pubkey} from [{fingerprint}] {path}");
+        }
+        for (pubkey, (_, (fingerprint, path))) in &output.tap_key_origins {
+            status!("       taproot key {pubkey} from [{fingerprint}] {path}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_decode_handles_padding() {
+        assert_eq!(base64_decode("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(base64_decode("aGVsbG8h").unwrap(), b"hello!");
+        assert_eq!(base64_decode("aGk=\n").unwrap(), b"hi");
+        assert_eq!(base64_decode("").unwrap(), b"");
+    }
+
+    #[test]
+    fn base64_decode_reads_the_psbt_magic() {
+        assert_eq!(base64_decode("cHNidP8=").unwrap(), b"psbt\xff");
+    }
+
+    #[test]
+    fn base64_decode_rejects_other_characters() {
+        assert_eq!(base64_decode("aGV*bG8="), None);
+        assert_eq!(base64_decode("aGV-bG8_"), None);
+    }
+}
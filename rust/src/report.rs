@@ -0,0 +1,1793 @@
+// The report written after the payment confirms, in every output format, and
+// the snapshots and checkpoints that feed into it.
+
+use bitcoincore_rpc::bitcoin::amount::serde::{as_btc, as_sat};
+use bitcoincore_rpc::bitcoin::script::Instruction;
+use bitcoincore_rpc::bitcoin::{
+    Address, Amount, BlockHash, Denomination, Network, ScriptBuf, Txid,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::*;
+use crate::{mining::*, psbt::*, transaction::*, wallet::*};
+
+// How long we give a webhook to accept the report
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Minimal HTTP/1.1 POST of a JSON body over a plain TCP connection, returning the
+// response status code. Only http:// URLs are supported.
+pub fn post_json(url: &str, body: &str) -> std::io::Result<u16> {
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid(format!("only http:// webhook URLs are supported: {url}")))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let host_port = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{authority}:80")
+    };
+    let address = host_port
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid(format!("could not resolve {authority}")))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, WEBHOOK_TIMEOUT)?;
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+
+    // We only care about the status line, e.g. "HTTP/1.1 200 OK"
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("malformed HTTP response".to_string()))
+}
+
+// The report's addresses come from different code paths (`require_network`,
+// `from_script`), so check once more that they all belong to `network`
+pub fn assert_network(addresses: &[&Address], network: Network) -> bitcoincore_rpc::Result<()> {
+    for address in addresses {
+        if !address.as_unchecked().is_valid_for_network(network) {
+            return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "Address {address} is not valid for network {network}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Everything `build_report` extracts about the Miner -> Trader payment. The
+// outputs are all written from the `Report` made from it.
+pub struct TxReport {
+    pub txid: Txid,
+    pub miner_input_address: Address,
+    pub miner_input_amount: Amount,
+    pub trader_output_address: Address,
+    pub trader_output_amount: Amount,
+    // None when the send has no change output; change_amount is zero then
+    pub change_address: Option<Address>,
+    pub change_amount: Amount,
+    pub fee: Amount,
+    pub block_height: u64,
+    pub block_hash: BlockHash,
+    // Position of the outputs in the transaction, wallets shuffle them for privacy
+    pub trader_vout: Option<u32>,
+    pub change_vout: Option<u32>,
+    // Number of coins the wallet selected to fund the payment
+    pub input_count: usize,
+    // Script type of the payment output, e.g. witness_v0_scripthash for multisig
+    pub trader_output_type: Option<String>,
+    // Total fees the miner collected in the confirming block, ours included
+    pub confirming_block_fees: Amount,
+    // Annotations stored in the Miner wallet with --comment/--comment-to
+    pub comment: Option<String>,
+    pub comment_to: Option<String>,
+    // The coins the payment spent, traced back to the blocks that mined them
+    pub funding_sources: Vec<FundingSource>,
+    // Wallets the run used, which carry a suffix under --run-id
+    pub miner_wallet: String,
+    pub trader_wallet: String,
+    // Blocks mined to fund the Miner before sending
+    pub blocks_mined: u64,
+    // Fee rate the transaction paid in sat/vB: fee / vsize
+    pub fee_rate: f64,
+    // The --fee-rate that was asked for, to hold fee_rate against
+    pub requested_fee_rate: Option<f64>,
+    // Virtual size of the transaction in vbytes
+    pub vsize: u64,
+    // BTC times blocks of age of the spent coins, with --report-cdd
+    pub coin_days_destroyed: Option<f64>,
+    // Fee and payment converted at the --price given, never looked up online
+    pub fee_usd: Option<f64>,
+    pub amount_usd: Option<f64>,
+    // Fresh Miner addresses the confirmation blocks paid their rewards to
+    pub reward_addresses: Vec<Address>,
+    // The send at each --checkpoints depth
+    pub checkpoints: Vec<Checkpoint>,
+    // Free-form metadata given with --tag, sorted by key
+    pub tags: BTreeMap<String, String>,
+    // Outputs without an address, such as OP_RETURN data carriers
+    pub unaddressed_outputs: Vec<UnaddressedOutput>,
+    // Every Trader address paid with --recipients, the trader_output_* one first
+    pub recipient_outputs: Vec<RecipientOutput>,
+    // The PSBT after each step of a --psbt send
+    pub psbt_stages: Vec<PsbtStage>,
+}
+
+// One Trader address the send paid and where in the transaction it landed
+#[derive(Serialize, Clone)]
+pub struct RecipientOutput {
+    pub address: Address,
+    #[serde(rename = "amount_sats", with = "as_sat")]
+    pub amount: Amount,
+    pub vout: Option<u32>,
+}
+
+impl RecipientOutput {
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        let mut w = ProtoWriter::default();
+        w.string(1, &self.address.to_string());
+        w.double(2, self.amount.to_btc());
+        w.optional_uint(3, self.vout.map(u64::from));
+        w.0
+    }
+}
+
+// An output whose script has no address form: OP_RETURN or non-standard
+#[derive(Serialize, Clone)]
+pub struct UnaddressedOutput {
+    pub vout: u32,
+    #[serde(rename = "value_sats", with = "as_sat")]
+    pub value: Amount,
+    // scriptPubKey type as the node names it, e.g. nulldata or nonstandard
+    pub script_type: String,
+    // For OP_RETURN the pushed data, otherwise the whole script, as hex
+    pub data_hex: String,
+}
+
+impl UnaddressedOutput {
+    pub fn from_vout(
+        vout: &bitcoincore_rpc::json::GetRawTransactionResultVout,
+    ) -> Option<UnaddressedOutput> {
+        if vout.script_pub_key.address.is_some() {
+            return None;
+        }
+        let script = ScriptBuf::from_bytes(vout.script_pub_key.hex.clone());
+        let data = if script.is_op_return() {
+            script
+                .instructions()
+                .skip(1)
+                .filter_map(|instruction| match instruction {
+                    Ok(Instruction::PushBytes(bytes)) => Some(bytes.as_bytes().to_vec()),
+                    _ => None,
+                })
+                .flatten()
+                .collect()
+        } else {
+            script.to_bytes()
+        };
+        let script_type = vout
+            .script_pub_key
+            .type_
+            .and_then(|t| serde_json::to_value(t).ok())
+            .and_then(|t| t.as_str().map(str::to_string))
+            .unwrap_or_else(|| "unknown".to_string());
+        Some(UnaddressedOutput {
+            vout: vout.n,
+            value: vout.value,
+            script_type,
+            data_hex: data.iter().map(|b| format!("{b:02x}")).collect(),
+        })
+    }
+
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        let mut w = ProtoWriter::default();
+        w.uint(1, u64::from(self.vout));
+        w.double(2, self.value.to_btc());
+        w.string(3, &self.script_type);
+        w.string(4, &self.data_hex);
+        w.0
+    }
+}
+
+// How change detection judged one output of the send, for --trace-change
+#[derive(Serialize)]
+pub struct ChangeTraceEntry {
+    pub vout: u32,
+    #[serde(with = "as_btc")]
+    pub value: Amount,
+    pub address: Option<String>,
+    pub matches_recipient: bool,
+    // Whether the Miner wallet owns the output and derived it as change, None
+    // without an address
+    pub is_mine: Option<bool>,
+    pub is_change: Option<bool>,
+    pub decision: &'static str,
+}
+
+// The send as seen at one --checkpoints depth
+#[derive(Serialize)]
+pub struct Checkpoint {
+    pub confirmations: i32,
+    pub block_hash: Option<BlockHash>,
+    pub block_height: Option<u32>,
+    pub tip_height: u64,
+    #[serde(rename = "trader_balance_sats", with = "as_sat")]
+    pub trader_balance: Amount,
+}
+
+pub fn checkpoint(
+    rpc: &impl rpc::BitcoinRpc,
+    miner: &impl rpc::BitcoinRpc,
+    trader: &impl rpc::BitcoinRpc,
+    txid: &Txid,
+) -> bitcoincore_rpc::Result<Checkpoint> {
+    let tx = miner.get_transaction(txid, None)?;
+    Ok(Checkpoint {
+        confirmations: tx.info.confirmations,
+        block_hash: tx.info.blockhash,
+        block_height: tx.info.blockheight,
+        tip_height: rpc.get_block_count()?,
+        trader_balance: trader.get_balance(None, None)?,
+    })
+}
+
+// Node and wallet state at one point of the run, for --snapshot-dir
+#[derive(Serialize)]
+pub struct ChainSnapshot {
+    pub stage: &'static str,
+    pub timestamp: u64,
+    pub blockchain_info: bitcoincore_rpc::json::GetBlockchainInfoResult,
+    pub mempool_info: bitcoincore_rpc::json::GetMempoolInfoResult,
+    #[serde(with = "as_btc")]
+    pub miner_balance: Amount,
+    #[serde(with = "as_btc")]
+    pub trader_balance: Amount,
+}
+
+// Write a ChainSnapshot to `<dir>/<timestamp>-<stage>.json`
+pub fn write_snapshot(
+    rpc: &impl rpc::BitcoinRpc,
+    miner: &impl rpc::BitcoinRpc,
+    trader: &impl rpc::BitcoinRpc,
+    dir: &str,
+    stage: &'static str,
+) -> bitcoincore_rpc::Result<()> {
+    let snapshot = ChainSnapshot {
+        stage,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        blockchain_info: rpc.get_blockchain_info()?,
+        mempool_info: rpc.get_mempool_info()?,
+        miner_balance: miner.get_balance(None, None)?,
+        trader_balance: trader.get_balance(None, None)?,
+    };
+    std::fs::create_dir_all(dir)?;
+    let path = format!("{dir}/{}-{stage}.json", snapshot.timestamp);
+    std::fs::write(&path, serde_json::to_string_pretty(&snapshot)?)?;
+    status!("Wrote {stage} snapshot to {path}");
+    Ok(())
+}
+
+// What a --no-send run produced: the funding half of the flow only
+#[derive(Serialize)]
+pub struct MiningReport {
+    pub miner_wallet: String,
+    pub miner_address: Address,
+    pub blocks_mined: u64,
+    pub block_height: u64,
+    #[serde(with = "as_btc")]
+    pub balance: Amount,
+    #[serde(with = "as_btc")]
+    pub immature: Amount,
+}
+
+// Protobuf wire encoding for --format protobuf, following proto/tx_report.proto.
+// Hand-written since the messages are small and flat: proto3 scalars are left
+// out when they hold their default value, `optional` ones whenever they are None.
+#[derive(Default)]
+struct ProtoWriter(Vec<u8>);
+
+impl ProtoWriter {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.optional_uint(field, Some(value));
+        }
+    }
+
+    fn optional_uint(&mut self, field: u32, value: Option<u64>) {
+        if let Some(value) = value {
+            self.key(field, 0);
+            self.varint(value);
+        }
+    }
+
+    fn int32(&mut self, field: u32, value: i32) {
+        // Negative int32 values are sign extended to ten bytes
+        self.uint(field, i64::from(value) as u64);
+    }
+
+    fn double(&mut self, field: u32, value: f64) {
+        if value != 0.0 {
+            self.optional_double(field, Some(value));
+        }
+    }
+
+    fn optional_double(&mut self, field: u32, value: Option<f64>) {
+        if let Some(value) = value {
+            self.key(field, 1);
+            self.0.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        if !value.is_empty() {
+            self.bytes(field, value.as_bytes());
+        }
+    }
+
+    fn optional_string(&mut self, field: u32, value: Option<&str>) {
+        if let Some(value) = value {
+            self.bytes(field, value.as_bytes());
+        }
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+}
+
+impl FundingSource {
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        let mut w = ProtoWriter::default();
+        w.string(1, &self.txid.to_string());
+        w.uint(2, u64::from(self.vout));
+        w.double(3, self.amount.to_btc());
+        w.optional_uint(4, self.coinbase_height);
+        w.optional_uint(5, self.created_height);
+        w.0
+    }
+}
+
+impl Checkpoint {
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        let mut w = ProtoWriter::default();
+        w.int32(1, self.confirmations);
+        w.optional_string(2, self.block_hash.map(|h| h.to_string()).as_deref());
+        w.optional_uint(3, self.block_height.map(u64::from));
+        w.uint(4, self.tip_height);
+        w.double(5, self.trader_balance.to_btc());
+        w.0
+    }
+}
+
+// A payment from the Miner to the Trader that has confirmed, and what the run
+// did to get there
+pub struct Payment {
+    pub txid: Txid,
+    pub block_hash: BlockHash,
+    pub miner_address: Address,
+    // Trader addresses and what each was paid, the out.txt one first
+    pub recipients: Vec<(Address, Amount)>,
+    // The PSBT after each step, when the send went through --psbt
+    pub psbt_stages: Vec<PsbtStage>,
+    pub miner_wallet: String,
+    pub trader_wallet: String,
+    pub blocks_mined: u64,
+    pub mempool_entry: bitcoincore_rpc::json::GetMempoolEntryResult,
+    pub reward_addresses: Vec<Address>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+// Extract all required transaction details of `payment` into the report that
+// out.txt and the other formats are written from
+pub fn build_report(
+    rpc: &impl rpc::BitcoinRpc,
+    miner: &impl rpc::BitcoinRpc,
+    payment: Payment,
+    options: &Options,
+) -> bitcoincore_rpc::Result<TxReport> {
+    let Payment {
+        txid,
+        block_hash,
+        miner_address: mining_reward_address,
+        recipients,
+        psbt_stages,
+        miner_wallet,
+        trader_wallet,
+        blocks_mined,
+        mempool_entry,
+        reward_addresses,
+        checkpoints,
+    } = payment;
+    let (trader_receive_address, send_amount) = recipients[0].clone();
+    let trader_address = trader_receive_address.as_unchecked().clone();
+
+    // Get the raw transaction first. Leave `iswitness` unset so the node tries
+    // both serializations instead of forcing a witness parse on a legacy one.
+    let raw_tx = miner.get_raw_transaction(&txid, None)?;
+    let decoded_tx = miner.decode_raw_transaction(&raw_tx, None)?;
+
+    status!("Transaction outputs:");
+    for (i, vout) in decoded_tx.vout.iter().enumerate() {
+        status!("Output {}: {:?}", i, vout.script_pub_key.address);
+    }
+    status!("Trader address: {trader_receive_address}");
+
+    // Find the change output: the one paying an address the Miner wallet owns
+    // and derived from its internal (change) keychain. Telling it apart from
+    // "whatever isn't the Trader" keeps batched sends and payments back to the
+    // Miner from being mistaken for change.
+    let trader_addr_str = trader_receive_address.to_string();
+
+    let mut change_address = None;
+    let mut change_amount = Amount::ZERO;
+    let mut change_vout = None;
+
+    // Ownership of every output with an address, from the Miner wallet's view
+    let mut ownership = Vec::new();
+    for vout in &decoded_tx.vout {
+        let Some(addr) = &vout.script_pub_key.address else {
+            ownership.push((vout, None, None, None));
+            continue;
+        };
+        let output_addr = addr.clone().require_network(options.network).map_err(|e| {
+            bitcoincore_rpc::Error::ReturnedError(format!("Failed to process output address: {e}"))
+        })?;
+        let info = address_ownership(miner, &output_addr)?;
+        status!(
+            "Checking output: {} BTC to {output_addr} (mine: {}, change: {})",
+            vout.value.to_btc(),
+            info.is_mine,
+            info.is_change
+        );
+        ownership.push((
+            vout,
+            Some(output_addr),
+            Some(info.is_mine),
+            Some(info.is_change),
+        ));
+    }
+
+    let change = ownership
+        .iter()
+        .find(|(_, _, is_mine, is_change)| *is_mine == Some(true) && *is_change == Some(true));
+    if let Some((vout, Some(address), _, _)) = change {
+        change_address = Some(address.clone());
+        change_amount = vout.value;
+        change_vout = Some(vout.n);
+        status!(
+            "Found change output: {} BTC to {address}",
+            change_amount.to_btc()
+        );
+    }
+
+    if options.trace_change {
+        let mut trace = Vec::new();
+        for (vout, address, is_mine, is_change) in &ownership {
+            let matches_recipient = address.as_ref() == Some(&trader_receive_address);
+            let decision = if Some(vout.n) == change_vout {
+                "change"
+            } else if matches_recipient {
+                "recipient"
+            } else if address.is_none() {
+                "skipped: no address"
+            } else if *is_mine != Some(true) {
+                "skipped: not the Miner's"
+            } else if *is_change != Some(true) {
+                "skipped: Miner receive address, not change"
+            } else {
+                "skipped: change already found"
+            };
+            trace.push(ChangeTraceEntry {
+                vout: vout.n,
+                value: vout.value,
+                address: address.as_ref().map(|a| a.to_string()),
+                matches_recipient,
+                is_mine: *is_mine,
+                is_change: *is_change,
+                decision,
+            });
+        }
+        // Next to out.txt, wherever output_path put that
+        let path = std::path::Path::new(&options.output_path).with_file_name("change-trace.json");
+        std::fs::write(&path, serde_json::to_string_pretty(&trace)?)?;
+        status!("Change detection trace written to {}", path.display());
+    }
+
+    // Where each recipient's payment landed, by the same address matching, and
+    // what it holds after --subtract-fee took its share of the fee
+    let recipient_outputs: Vec<RecipientOutput> = recipients
+        .iter()
+        .map(|(address, amount)| {
+            let output = decoded_tx
+                .vout
+                .iter()
+                .find(|v| v.script_pub_key.address.as_ref() == Some(address.as_unchecked()));
+            RecipientOutput {
+                address: address.clone(),
+                amount: output.map_or(*amount, |v| v.value),
+                vout: output.map(|v| v.n),
+            }
+        })
+        .collect();
+    for output in recipient_outputs.iter().skip(1) {
+        status!(
+            "Also paid {} BTC to {} (output {:?})",
+            output.amount.to_btc(),
+            output.address,
+            output.vout
+        );
+    }
+
+    // The trader's output index, by the same address matching
+    let trader_output = decoded_tx
+        .vout
+        .iter()
+        .find(|v| v.script_pub_key.address.as_ref() == Some(&trader_address));
+    let trader_vout = trader_output.map(|v| v.n);
+    let trader_output_type = trader_output
+        .and_then(|v| v.script_pub_key.type_)
+        .and_then(|t| serde_json::to_value(t).ok())
+        .and_then(|t| t.as_str().map(str::to_string));
+
+    // Data carriers and odd scripts have no address to classify by, keep them
+    // in the report rather than dropping them
+    let unaddressed_outputs: Vec<UnaddressedOutput> = decoded_tx
+        .vout
+        .iter()
+        .filter_map(UnaddressedOutput::from_vout)
+        .collect();
+    for output in &unaddressed_outputs {
+        status!(
+            "Output {} has no address: {} BTC, {} script, data {}",
+            output.vout,
+            output.value.to_btc(),
+            output.script_type,
+            output.data_hex
+        );
+    }
+
+    // A send can legitimately have no change: the inputs covered the payment
+    // and fee exactly, or the fee came out of the payment itself
+    if change_vout.is_none() {
+        status!("No change output: the inputs were spent entirely on the payment and fee");
+
+        // Show the outputs, in case the change went unrecognized
+        status!("All transaction outputs:");
+        for (i, vout) in decoded_tx.vout.iter().enumerate() {
+            status!("  Output {}: {} BTC", i, vout.value.to_btc());
+            if let Some(addr) = &vout.script_pub_key.address {
+                let addr_str = addr
+                    .clone()
+                    .require_network(options.network)
+                    .map_err(|e| {
+                        bitcoincore_rpc::Error::ReturnedError(format!("Address error: {e}"))
+                    })?
+                    .to_string();
+                status!("    Address: {addr_str}");
+                status!("    Is trader address? {}", addr_str == trader_addr_str);
+            }
+        }
+    }
+
+    // Get transaction details using the miner client (since it sent the transaction)
+    let tx_details = miner.get_transaction(&txid, Some(true))?;
+    let raw_tx_info = rpc.get_raw_transaction_info(&txid, None)?;
+
+    // Show the transaction's id both with and without its witness data
+    let wtxid = raw_tx_info.hash;
+    let ids_differ = raw_tx_info.txid.to_string() != wtxid.to_string();
+    status!("txid:  {}", raw_tx_info.txid);
+    status!("wtxid: {wtxid} (differs from txid: {ids_differ})");
+    debug(
+        "The txid hashes the transaction without its witness (signatures), so it can't be \
+         changed by re-encoding a signature. The wtxid also covers the witness and is what the \
+         coinbase's witness commitment commits to. For SegWit spends the two differ; for a \
+         transaction without witness data they are identical.",
+    );
+    let block_info = rpc.get_block(&block_hash)?;
+    // Height of the block that confirmed the transaction, which is not necessarily the tip
+    let block_height = height_for_hash(rpc, &block_hash)?;
+
+    // Extract input information
+    let input_amount = tx_details
+        .details
+        .iter()
+        .find(|d| d.category == bitcoincore_rpc::json::GetTransactionResultDetailCategory::Send)
+        .and_then(|d| d.amount.abs().to_unsigned().ok())
+        .unwrap_or(Amount::ZERO);
+
+    // What the Trader actually got, less than was asked for with --subtract-fee
+    let output_amount = trader_output.map_or(send_amount, |v| v.value);
+    let fee = tx_details
+        .fee
+        .and_then(|fee| fee.abs().to_unsigned().ok())
+        .unwrap_or(Amount::ZERO);
+
+    if options.subtract_fee {
+        status!(
+            "The fee of {} BTC came out of the payment, the Trader got {} BTC of the {} BTC sent",
+            fee.to_btc(),
+            output_amount.to_btc(),
+            send_amount.to_btc()
+        );
+    }
+
+    status!("Looking for change address (trader address: {trader_addr_str})");
+    match &change_address {
+        Some(address) => status!("Change address: {address}"),
+        None => status!("Change address: {NO_CHANGE}"),
+    }
+
+    // Read the annotations back from the wallet rather than echoing our options
+    let (comment, comment_to) = transaction_comments(miner, &txid)?;
+    let funding_sources = trace_funding(rpc, &decoded_tx)?;
+    reconcile_fee(fee, &funding_sources, &decoded_tx);
+    let coin_days_destroyed = options
+        .report_cdd
+        .then(|| coin_days_destroyed(&funding_sources, block_height));
+
+    Ok(TxReport {
+        txid,
+        miner_input_address: mining_reward_address.clone(),
+        miner_input_amount: input_amount,
+        trader_output_address: trader_receive_address.clone(),
+        trader_output_amount: output_amount,
+        change_address,
+        change_amount,
+        fee,
+        block_height,
+        block_hash,
+        trader_vout,
+        change_vout,
+        input_count: decoded_tx.vin.len(),
+        trader_output_type,
+        confirming_block_fees: block_fees(&block_info, block_height),
+        comment,
+        comment_to,
+        funding_sources,
+        miner_wallet,
+        trader_wallet,
+        blocks_mined,
+        fee_rate: effective_fee_rates(&mempool_entry).individual,
+        requested_fee_rate: options.fee_rate,
+        vsize: mempool_entry.vsize,
+        coin_days_destroyed,
+        fee_usd: options.price.map(|price| fee.to_btc() * price),
+        amount_usd: options.price.map(|price| output_amount.to_btc() * price),
+        reward_addresses,
+        checkpoints,
+        tags: options.tags.clone(),
+        unaddressed_outputs,
+        recipient_outputs,
+        psbt_stages,
+    })
+}
+
+// An out.txt line that disagrees with the chain
+pub struct Mismatch {
+    // 1-based, like an editor shows it
+    pub line: usize,
+    pub field: &'static str,
+    pub out_txt: String,
+    pub chain: String,
+}
+
+// Check an out.txt written by an earlier run against what the node has for its
+// transaction: the block it confirmed in, both outputs, the spent coins and the
+// fee they imply. Returns every disagreement, an empty list means it all holds.
+pub fn verify_out_txt(
+    rpc: &impl rpc::BitcoinRpc,
+    text: &str,
+) -> bitcoincore_rpc::Result<Vec<Mismatch>> {
+    let lines: Vec<&str> = text.lines().map(str::trim).collect();
+    if lines.len() != 10 {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Expected the 10 lines of out.txt, found {}",
+            lines.len()
+        )));
+    }
+    let invalid = |line: usize, what: &str| {
+        bitcoincore_rpc::Error::ReturnedError(format!(
+            "Line {} is not a valid {what}: {}",
+            line + 1,
+            lines[line]
+        ))
+    };
+    let amount = |line: usize| {
+        Amount::from_str_in(lines[line], Denomination::Bitcoin)
+            .map_err(|_| invalid(line, "BTC amount"))
+    };
+    let txid: Txid = lines[0].parse().map_err(|_| invalid(0, "txid"))?;
+    let height: u64 = lines[8].parse().map_err(|_| invalid(8, "block height"))?;
+
+    let mut mismatches = Vec::new();
+    let mut differ = |line: usize, field: &'static str, chain: String| {
+        mismatches.push(Mismatch {
+            line: line + 1,
+            field,
+            out_txt: lines[line].to_string(),
+            chain,
+        })
+    };
+
+    let tx = rpc.get_raw_transaction_info(&txid, None)?;
+    match tx.blockhash {
+        Some(hash) => {
+            if hash.to_string() != lines[9] {
+                differ(9, "block hash", hash.to_string());
+            }
+            let actual_height = height_for_hash(rpc, &hash)?;
+            if actual_height != height {
+                differ(8, "block height", actual_height.to_string());
+            }
+        }
+        None => differ(9, "block hash", "not confirmed yet".to_string()),
+    }
+
+    let address_of = |script: &bitcoincore_rpc::json::GetRawTransactionResultVoutScriptPubKey| {
+        script
+            .address
+            .clone()
+            .map(|a| a.assume_checked().to_string())
+    };
+    for (address_line, field) in [(3, "trader output"), (5, "change output")] {
+        // A changeless send writes NO_CHANGE and a zero amount
+        if lines[address_line] == NO_CHANGE {
+            if amount(address_line + 1)? != Amount::ZERO {
+                differ(
+                    address_line + 1,
+                    field,
+                    "0 for a send without change".to_string(),
+                );
+            }
+            continue;
+        }
+        let output = tx
+            .vout
+            .iter()
+            .find(|out| address_of(&out.script_pub_key).as_deref() == Some(lines[address_line]));
+        match output {
+            Some(out) if out.value != amount(address_line + 1)? => {
+                differ(address_line + 1, field, out.value.to_btc().to_string())
+            }
+            Some(_) => {}
+            None => differ(
+                address_line,
+                field,
+                "no output pays this address".to_string(),
+            ),
+        }
+    }
+
+    // The spent coins, with their addresses, in one batch. Inputs without a
+    // txid (coinbase) aren't looked up, but keep their place so every other
+    // input lines up with its own previous transaction.
+    let args: Vec<_> = tx
+        .vin
+        .iter()
+        .filter_map(|input| Some(vec![json!(input.txid?), json!(true)]))
+        .collect();
+    let mut prevs: std::vec::IntoIter<bitcoincore_rpc::json::GetRawTransactionResult> =
+        rpc.call_batch("getrawtransaction", &args)?.into_iter();
+    let prevs: Vec<Option<_>> = tx
+        .vin
+        .iter()
+        .map(|input| input.txid.and_then(|_| prevs.next()))
+        .collect();
+    let spent: Vec<_> = tx
+        .vin
+        .iter()
+        .zip(&prevs)
+        .filter_map(|(input, prev)| {
+            prev.as_ref()?
+                .vout
+                .iter()
+                .find(|out| Some(out.n) == input.vout)
+        })
+        .collect();
+    let input_total: Amount = spent.iter().map(|out| out.value).sum();
+    let input_addresses: Vec<String> = spent
+        .iter()
+        .filter_map(|out| address_of(&out.script_pub_key))
+        .collect();
+    if !input_addresses.iter().any(|a| a == lines[1]) {
+        differ(1, "miner input address", input_addresses.join(", "));
+    }
+    let input_amount = amount(2)?;
+    if input_amount == Amount::ZERO || input_amount > input_total {
+        differ(
+            2,
+            "miner input amount",
+            format!("inputs worth {} BTC", input_total.to_btc()),
+        );
+    }
+
+    let output_total: Amount = tx.vout.iter().map(|out| out.value).sum();
+    let fee = input_total
+        .checked_sub(output_total)
+        .unwrap_or(Amount::ZERO);
+    if fee != amount(7)? {
+        differ(7, "fee", fee.to_btc().to_string());
+    }
+    Ok(mismatches)
+}
+
+// Bumped whenever a field of Report is renamed, removed or changes meaning, so
+// consumers of the JSON and CSV output can tell which layout they're reading.
+// Version 2 made change_address null for sends without change. Version 3 added
+// the rest of the run detail and moved the nested amounts to satoshis too.
+pub const REPORT_SCHEMA_VERSION: u32 = 3;
+
+// What out.txt, CSV and --field show in place of the change address when the
+// send has no change output
+pub const NO_CHANGE: &str = "none";
+
+// The result of a run, as written to out.txt and every other output: the JSON
+// formats, CSV, protobuf, --field and --metrics-out. Amounts are exact, and
+// serialize as satoshis.
+#[derive(Serialize)]
+pub struct Report {
+    pub schema_version: u32,
+    pub txid: Txid,
+    pub miner_input_address: Address,
+    #[serde(with = "as_sat")]
+    pub miner_input_sats: Amount,
+    pub trader_output_address: Address,
+    #[serde(with = "as_sat")]
+    pub trader_output_sats: Amount,
+    pub trader_vout: Option<u32>,
+    pub change_address: Option<Address>,
+    #[serde(with = "as_sat")]
+    pub change_sats: Amount,
+    pub change_vout: Option<u32>,
+    #[serde(with = "as_sat")]
+    pub fee_sats: Amount,
+    pub fee_rate: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_fee_rate: Option<f64>,
+    pub vsize: u64,
+    pub input_count: usize,
+    // Script type of the payment output, e.g. witness_v0_scripthash for multisig
+    pub trader_output_type: Option<String>,
+    pub block_hash: BlockHash,
+    pub block_height: u64,
+    // Unix timestamps of the confirming block and of the end of the run
+    pub block_time: u64,
+    pub finished_at: u64,
+    // Total fees the miner collected in the confirming block, ours included
+    #[serde(with = "as_sat")]
+    pub confirming_block_fees_sats: Amount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment_to: Option<String>,
+    pub funding_sources: Vec<FundingSource>,
+    pub miner_wallet: String,
+    pub trader_wallet: String,
+    pub blocks_mined: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coin_days_destroyed: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_usd: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_usd: Option<f64>,
+    pub reward_addresses: Vec<Address>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub checkpoints: Vec<Checkpoint>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unaddressed_outputs: Vec<UnaddressedOutput>,
+    pub recipient_outputs: Vec<RecipientOutput>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub psbt_stages: Vec<PsbtStage>,
+}
+
+impl Report {
+    pub fn new(report: TxReport, block_time: u64) -> Self {
+        Report {
+            schema_version: REPORT_SCHEMA_VERSION,
+            txid: report.txid,
+            miner_input_address: report.miner_input_address,
+            miner_input_sats: report.miner_input_amount,
+            trader_output_address: report.trader_output_address,
+            trader_output_sats: report.trader_output_amount,
+            trader_vout: report.trader_vout,
+            change_address: report.change_address,
+            change_sats: report.change_amount,
+            change_vout: report.change_vout,
+            fee_sats: report.fee,
+            fee_rate: report.fee_rate,
+            requested_fee_rate: report.requested_fee_rate,
+            vsize: report.vsize,
+            input_count: report.input_count,
+            trader_output_type: report.trader_output_type,
+            block_hash: report.block_hash,
+            block_height: report.block_height,
+            block_time,
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            confirming_block_fees_sats: report.confirming_block_fees,
+            comment: report.comment,
+            comment_to: report.comment_to,
+            funding_sources: report.funding_sources,
+            miner_wallet: report.miner_wallet,
+            trader_wallet: report.trader_wallet,
+            blocks_mined: report.blocks_mined,
+            coin_days_destroyed: report.coin_days_destroyed,
+            fee_usd: report.fee_usd,
+            amount_usd: report.amount_usd,
+            reward_addresses: report.reward_addresses,
+            checkpoints: report.checkpoints,
+            tags: report.tags,
+            unaddressed_outputs: report.unaddressed_outputs,
+            recipient_outputs: report.recipient_outputs,
+            psbt_stages: report.psbt_stages,
+        }
+    }
+
+    // The out.txt format given in readme.md, one attribute per line, amounts in BTC
+    pub fn out_txt(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            self.txid,
+            self.miner_input_address,
+            self.miner_input_sats.to_btc(),
+            self.trader_output_address,
+            self.trader_output_sats.to_btc(),
+            self.change_address
+                .as_ref()
+                .map_or(NO_CHANGE.to_string(), |a| a.to_string()),
+            self.change_sats.to_btc(),
+            self.fee_sats.to_btc(),
+            self.block_height,
+            self.block_hash
+        )
+    }
+
+    pub const CSV_COLUMNS: [&'static str; 22] = [
+        "schema_version",
+        "txid",
+        "miner_input_address",
+        "miner_input_sats",
+        "trader_output_address",
+        "trader_output_sats",
+        "trader_vout",
+        "change_address",
+        "change_sats",
+        "change_vout",
+        "fee_sats",
+        "fee_rate",
+        "vsize",
+        "input_count",
+        "block_hash",
+        "block_height",
+        "block_time",
+        "finished_at",
+        "miner_wallet",
+        "trader_wallet",
+        "blocks_mined",
+        "tags",
+    ];
+
+    // The values for CSV_COLUMNS, in order. Tags are joined as key=value;...
+    pub fn csv_row(&self) -> Vec<String> {
+        let optional = |n: Option<u32>| n.map_or(String::new(), |n| n.to_string());
+        let tags: Vec<String> = self.tags.iter().map(|(k, v)| format!("{k}={v}")).collect();
+        vec![
+            self.schema_version.to_string(),
+            self.txid.to_string(),
+            self.miner_input_address.to_string(),
+            self.miner_input_sats.to_sat().to_string(),
+            self.trader_output_address.to_string(),
+            self.trader_output_sats.to_sat().to_string(),
+            optional(self.trader_vout),
+            self.change_address
+                .as_ref()
+                .map_or(NO_CHANGE.to_string(), |a| a.to_string()),
+            self.change_sats.to_sat().to_string(),
+            optional(self.change_vout),
+            self.fee_sats.to_sat().to_string(),
+            self.fee_rate.to_string(),
+            self.vsize.to_string(),
+            self.input_count.to_string(),
+            self.block_hash.to_string(),
+            self.block_height.to_string(),
+            self.block_time.to_string(),
+            self.finished_at.to_string(),
+            self.miner_wallet.to_string(),
+            self.trader_wallet.to_string(),
+            self.blocks_mined.to_string(),
+            tags.join(";"),
+        ]
+    }
+}
+
+// Quote a CSV field when it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn csv_line(fields: &[String]) -> String {
+    fields
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Add `report` as a row to the CSV file at `path`, starting the file with the
+// header row if it is new or empty, so repeated runs build up one table
+pub fn append_csv(path: &str, report: &Report) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() == 0 {
+        let header: Vec<String> = Report::CSV_COLUMNS.iter().map(|c| c.to_string()).collect();
+        writeln!(file, "{}", csv_line(&header))?;
+    }
+    writeln!(file, "{}", csv_line(&report.csv_row()))
+}
+
+impl Report {
+    pub const FIELDS: [&'static str; 33] = [
+        "txid",
+        "miner_input_address",
+        "miner_input_amount",
+        "trader_output_address",
+        "trader_output_amount",
+        "change_address",
+        "change_amount",
+        "fee",
+        "block_height",
+        "block_hash",
+        "trader_vout",
+        "change_vout",
+        "input_count",
+        "trader_output_type",
+        "confirming_block_fees",
+        "comment",
+        "comment_to",
+        "funding_sources",
+        "miner_wallet",
+        "trader_wallet",
+        "blocks_mined",
+        "fee_rate",
+        "vsize",
+        "coin_days_destroyed",
+        "fee_usd",
+        "amount_usd",
+        "reward_addresses",
+        "checkpoints",
+        "tags",
+        "unaddressed_outputs",
+        "recipient_outputs",
+        "requested_fee_rate",
+        "psbt_stages",
+    ];
+
+    // A single field by name, formatted as in out.txt. Optional fields that
+    // are absent come out as an empty string.
+    pub fn field(&self, name: &str) -> Option<String> {
+        let optional = |v: Option<u32>| v.map_or(String::new(), |n| n.to_string());
+        Some(match name {
+            "txid" => self.txid.to_string(),
+            "miner_input_address" => self.miner_input_address.to_string(),
+            "miner_input_amount" => self.miner_input_sats.to_btc().to_string(),
+            "trader_output_address" => self.trader_output_address.to_string(),
+            "trader_output_amount" => self.trader_output_sats.to_btc().to_string(),
+            "change_address" => self
+                .change_address
+                .as_ref()
+                .map_or(NO_CHANGE.to_string(), |a| a.to_string()),
+            "change_amount" => self.change_sats.to_btc().to_string(),
+            "fee" => self.fee_sats.to_btc().to_string(),
+            "block_height" => self.block_height.to_string(),
+            "block_hash" => self.block_hash.to_string(),
+            "trader_vout" => optional(self.trader_vout),
+            "change_vout" => optional(self.change_vout),
+            "input_count" => self.input_count.to_string(),
+            "trader_output_type" => self.trader_output_type.clone().unwrap_or_default(),
+            "confirming_block_fees" => self.confirming_block_fees_sats.to_btc().to_string(),
+            "comment" => self.comment.clone().unwrap_or_default(),
+            "comment_to" => self.comment_to.clone().unwrap_or_default(),
+            "funding_sources" => self
+                .funding_sources
+                .iter()
+                .map(|s| format!("{}:{}", s.txid, s.vout))
+                .collect::<Vec<_>>()
+                .join(","),
+            "miner_wallet" => self.miner_wallet.clone(),
+            "trader_wallet" => self.trader_wallet.clone(),
+            "blocks_mined" => self.blocks_mined.to_string(),
+            "fee_rate" => self.fee_rate.to_string(),
+            "requested_fee_rate" => self
+                .requested_fee_rate
+                .map_or(String::new(), |rate| rate.to_string()),
+            "vsize" => self.vsize.to_string(),
+            "coin_days_destroyed" => self
+                .coin_days_destroyed
+                .map_or(String::new(), |cdd| cdd.to_string()),
+            "fee_usd" => self
+                .fee_usd
+                .map_or(String::new(), |usd| format!("{usd:.2}")),
+            "amount_usd" => self
+                .amount_usd
+                .map_or(String::new(), |usd| format!("{usd:.2}")),
+            "reward_addresses" => self
+                .reward_addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            "checkpoints" => self
+                .checkpoints
+                .iter()
+                .map(|c| c.confirmations.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            "tags" => self
+                .tags
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>()
+                .join(","),
+            "unaddressed_outputs" => self
+                .unaddressed_outputs
+                .iter()
+                .map(|o| format!("{}:{}:{}", o.vout, o.script_type, o.data_hex))
+                .collect::<Vec<_>>()
+                .join(","),
+            "psbt_stages" => self
+                .psbt_stages
+                .iter()
+                .map(|s| format!("{}:{}", s.stage, s.psbt))
+                .collect::<Vec<_>>()
+                .join(","),
+            "recipient_outputs" => self
+                .recipient_outputs
+                .iter()
+                .map(|o| format!("{}:{}", o.address, o.amount.to_btc()))
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => return None,
+        })
+    }
+
+    // Prometheus exposition format of the run, for --metrics-out
+    pub fn metrics(&self, rpc_calls: u64, duration: Duration) -> String {
+        let fee_sat = self.fee_sats.to_sat();
+        [
+            (
+                "capstone_blocks_mined",
+                "Blocks mined to fund the Miner",
+                "gauge",
+                self.blocks_mined.to_string(),
+            ),
+            (
+                "capstone_fee_sat",
+                "Fee paid by the send in satoshis",
+                "gauge",
+                fee_sat.to_string(),
+            ),
+            (
+                "capstone_tx_vsize",
+                "Virtual size of the send in vbytes",
+                "gauge",
+                self.vsize.to_string(),
+            ),
+            (
+                "capstone_rpc_calls_total",
+                "RPC requests sent to the node",
+                "counter",
+                rpc_calls.to_string(),
+            ),
+            (
+                "capstone_run_duration_seconds",
+                "Wall clock time of the run",
+                "gauge",
+                duration.as_secs_f64().to_string(),
+            ),
+        ]
+        .iter()
+        .map(|(name, help, kind, value)| {
+            format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n")
+        })
+        .collect()
+    }
+
+    // The report as a `TxReport` message of proto/tx_report.proto
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        let mut w = ProtoWriter::default();
+        w.string(1, &self.txid.to_string());
+        w.string(2, &self.miner_input_address.to_string());
+        w.double(3, self.miner_input_sats.to_btc());
+        w.string(4, &self.trader_output_address.to_string());
+        w.double(5, self.trader_output_sats.to_btc());
+        w.optional_string(
+            6,
+            self.change_address
+                .as_ref()
+                .map(|a| a.to_string())
+                .as_deref(),
+        );
+        w.double(7, self.change_sats.to_btc());
+        w.double(8, self.fee_sats.to_btc());
+        w.uint(9, self.block_height);
+        w.string(10, &self.block_hash.to_string());
+        w.optional_uint(11, self.trader_vout.map(u64::from));
+        w.optional_uint(12, self.change_vout.map(u64::from));
+        w.uint(13, self.input_count as u64);
+        w.optional_string(14, self.trader_output_type.as_deref());
+        w.double(15, self.confirming_block_fees_sats.to_btc());
+        w.optional_string(16, self.comment.as_deref());
+        w.optional_string(17, self.comment_to.as_deref());
+        for source in &self.funding_sources {
+            w.bytes(18, &source.to_protobuf());
+        }
+        w.string(19, &self.miner_wallet);
+        w.string(20, &self.trader_wallet);
+        w.uint(21, self.blocks_mined);
+        w.double(22, self.fee_rate);
+        w.uint(23, self.vsize);
+        w.optional_double(24, self.coin_days_destroyed);
+        w.optional_double(25, self.fee_usd);
+        w.optional_double(26, self.amount_usd);
+        for address in &self.reward_addresses {
+            w.bytes(27, address.to_string().as_bytes());
+        }
+        for checkpoint in &self.checkpoints {
+            w.bytes(28, &checkpoint.to_protobuf());
+        }
+        // Map fields go on the wire as repeated key/value entry messages
+        for (key, value) in &self.tags {
+            let mut entry = ProtoWriter::default();
+            entry.string(1, key);
+            entry.string(2, value);
+            w.bytes(29, &entry.0);
+        }
+        for output in &self.unaddressed_outputs {
+            w.bytes(30, &output.to_protobuf());
+        }
+        for output in &self.recipient_outputs {
+            w.bytes(31, &output.to_protobuf());
+        }
+        w.optional_double(32, self.requested_fee_rate);
+        for stage in &self.psbt_stages {
+            let mut entry = ProtoWriter::default();
+            entry.string(1, &stage.stage);
+            entry.string(2, &stage.psbt);
+            entry.uint(3, u64::from(stage.complete));
+            w.bytes(33, &entry.0);
+        }
+        w.uint(34, u64::from(self.schema_version));
+        w.0
+    }
+
+    // The run told as one sentence, for --format summary
+    pub fn summary(&self) -> String {
+        let mining = match self.blocks_mined {
+            0 => "Reused the existing balance of".to_string(),
+            1 => "Mined 1 block to".to_string(),
+            n => format!("Mined {n} blocks to"),
+        };
+        let change = match &self.change_address {
+            Some(address) => format!(
+                "returning {} BTC change to {address}",
+                self.change_sats.to_btc()
+            ),
+            None => "with no change".to_string(),
+        };
+        format!(
+            "{mining} {}, sent {} BTC to {} in tx {}, confirmed at height {} with a fee of {} BTC \
+             ({:.2} sat/vB), {change}.",
+            self.miner_input_address,
+            self.trader_output_sats.to_btc(),
+            self.trader_output_address,
+            self.txid,
+            self.block_height,
+            self.fee_sats.to_btc(),
+            self.fee_rate,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::stub::StubRpc;
+    use crate::rpc::BitcoinRpc;
+    use bitcoincore_rpc::bitcoin::absolute::LockTime;
+    use bitcoincore_rpc::bitcoin::block::{self, Header};
+    use bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{
+        transaction, Block, CompactTarget, OutPoint, PubkeyHash, Sequence, Transaction, TxIn,
+        TxMerkleNode, TxOut, WPubkeyHash, Witness,
+    };
+    use serde_json::Value;
+
+    fn address(byte: u8) -> Address {
+        let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([byte; 20]));
+        Address::from_script(&script, Network::Regtest).unwrap()
+    }
+
+    fn legacy_address(byte: u8) -> Address {
+        let script = ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([byte; 20]));
+        Address::from_script(&script, Network::Regtest).unwrap()
+    }
+
+    fn coinbase(height: u8, value: Amount, to: &Address) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(vec![0x01, height]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: to.script_pubkey(),
+            }],
+        }
+    }
+
+    // `tx` the way decoderawtransaction shows it
+    fn decoded(tx: &Transaction) -> Value {
+        let vin: Vec<Value> = tx
+            .input
+            .iter()
+            .map(|input| {
+                let mut vin = if input.previous_output.is_null() {
+                    json!({ "coinbase": input.script_sig.to_hex_string() })
+                } else {
+                    json!({
+                        "txid": input.previous_output.txid,
+                        "vout": input.previous_output.vout,
+                        "scriptSig": { "asm": "", "hex": input.script_sig.to_hex_string() },
+                    })
+                };
+                if !input.witness.is_empty() {
+                    let witness: Vec<String> = input
+                        .witness
+                        .iter()
+                        .map(|item| item.iter().map(|b| format!("{b:02x}")).collect())
+                        .collect();
+                    vin["txinwitness"] = json!(witness);
+                }
+                vin["sequence"] = json!(input.sequence.0);
+                vin
+            })
+            .collect();
+        let vout: Vec<Value> = tx
+            .output
+            .iter()
+            .enumerate()
+            .map(|(n, output)| {
+                let address = Address::from_script(&output.script_pubkey, Network::Regtest).ok();
+                json!({
+                    "value": output.value.to_btc(),
+                    "n": n,
+                    "scriptPubKey": {
+                        "asm": "",
+                        "hex": output.script_pubkey.to_hex_string(),
+                        "type": if output.script_pubkey.is_p2pkh() { "pubkeyhash" } else { "witness_v0_keyhash" },
+                        "address": address,
+                    },
+                })
+            })
+            .collect();
+        json!({
+            "txid": tx.txid(),
+            "hash": tx.wtxid(),
+            "size": tx.total_size(),
+            "vsize": tx.vsize(),
+            "weight": tx.weight().to_wu(),
+            "version": tx.version.0,
+            "locktime": tx.lock_time.to_consensus_u32(),
+            "vin": vin,
+            "vout": vout,
+        })
+    }
+
+    // `tx` the way verbose getrawtransaction shows it, confirmed in `block_hash`
+    fn raw_info(tx: &Transaction, block_hash: BlockHash) -> Value {
+        let mut info = decoded(tx);
+        info["hex"] = json!(serialize_hex(tx));
+        info["blockhash"] = json!(block_hash);
+        info["confirmations"] = json!(1);
+        info["time"] = json!(1_700_000_000);
+        info["blocktime"] = json!(1_700_000_000);
+        info
+    }
+
+    fn header(hash: BlockHash, height: u64) -> Value {
+        json!({
+            "hash": hash,
+            "confirmations": 1,
+            "height": height,
+            "version": 0x2000_0000,
+            "merkleroot": TxMerkleNode::all_zeros(),
+            "time": 1_700_000_000,
+            "mediantime": 1_700_000_000,
+            "nonce": 0,
+            "bits": "207fffff",
+            "difficulty": 4.656542373906925e-10,
+            "chainwork": "00",
+            "nTx": 2,
+        })
+    }
+
+    // A node and wallet that saw the Miner pay the Trader 20 BTC out of the 50
+    // BTC block `reward` in `spend`, confirmed at height 102, and the Payment
+    // the run would have made of it
+    fn fixture(reward: Transaction, spend: Transaction, fee: Amount) -> (StubRpc, Payment) {
+        let miner =
+            Address::from_script(&reward.output[0].script_pubkey, Network::Regtest).unwrap();
+        let trader = address(2);
+        let change = address(3);
+
+        let reward_block = BlockHash::from_byte_array([1; 32]);
+        let confirming = Block {
+            header: Header {
+                version: block::Version::TWO,
+                prev_blockhash: BlockHash::from_byte_array([101; 32]),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 1_700_000_000,
+                bits: CompactTarget::from_consensus(0x207f_ffff),
+                nonce: 0,
+            },
+            txdata: vec![
+                coinbase(102, Amount::from_int_btc(50) + fee, &miner),
+                spend.clone(),
+            ],
+        };
+        let block_hash = confirming.block_hash();
+        let txid = spend.txid();
+
+        let stub = StubRpc::new()
+            .respond(
+                &format!("getrawtransaction {txid} false"),
+                json!(serialize_hex(&spend)),
+            )
+            .respond(
+                &format!("getrawtransaction {txid} true"),
+                raw_info(&spend, block_hash),
+            )
+            // Only answered with iswitness unset, for the node to detect
+            .respond(
+                &format!("decoderawtransaction {} null", serialize_hex(&spend)),
+                decoded(&spend),
+            )
+            .respond(
+                &format!("getaddressinfo {trader}"),
+                json!({ "ismine": false }),
+            )
+            .respond(
+                &format!("getaddressinfo {change}"),
+                json!({ "ismine": true, "ischange": true }),
+            )
+            .respond(
+                &format!("gettransaction {txid}"),
+                json!({
+                    "amount": -20.0,
+                    "fee": -fee.to_btc(),
+                    "confirmations": 1,
+                    "blockhash": block_hash,
+                    "blockheight": 102,
+                    "blockindex": 1,
+                    "blocktime": 1_700_000_000,
+                    "txid": txid,
+                    "walletconflicts": [],
+                    "time": 1_700_000_000,
+                    "timereceived": 1_700_000_000,
+                    "bip125-replaceable": "no",
+                    "details": [{
+                        "address": trader,
+                        "category": "send",
+                        "amount": -20.0,
+                        "vout": 0,
+                        "fee": -fee.to_btc(),
+                        "abandoned": false,
+                    }],
+                    "hex": serialize_hex(&spend),
+                }),
+            )
+            .respond(
+                &format!("getmempoolentry {txid}"),
+                json!({
+                    "vsize": spend.vsize(),
+                    "weight": spend.weight().to_wu(),
+                    "time": 1_700_000_000,
+                    "height": 101,
+                    "descendantcount": 1,
+                    "descendantsize": spend.vsize(),
+                    "ancestorcount": 1,
+                    "ancestorsize": spend.vsize(),
+                    "wtxid": spend.wtxid(),
+                    "fees": {
+                        "base": fee.to_btc(),
+                        "modified": fee.to_btc(),
+                        "ancestor": fee.to_btc(),
+                        "descendant": fee.to_btc(),
+                    },
+                    "depends": [],
+                    "spentby": [],
+                    "bip125-replaceable": false,
+                    "unbroadcast": false,
+                }),
+            )
+            .respond(
+                &format!("getblock {block_hash} 0"),
+                json!(serialize_hex(&confirming)),
+            )
+            .respond(
+                &format!("getblockheader {block_hash}"),
+                header(block_hash, 102),
+            )
+            .respond(
+                &format!("getrawtransaction {} true", reward.txid()),
+                raw_info(&reward, reward_block),
+            )
+            .respond(
+                &format!("getblockheader {reward_block}"),
+                header(reward_block, 1),
+            );
+
+        let mempool_entry = stub.get_mempool_entry(&txid).unwrap();
+        let payment = Payment {
+            txid,
+            block_hash,
+            miner_address: miner,
+            recipients: vec![(trader, Amount::from_int_btc(20))],
+            psbt_stages: Vec::new(),
+            miner_wallet: "Miner".to_string(),
+            trader_wallet: "Trader".to_string(),
+            blocks_mined: 101,
+            mempool_entry,
+            reward_addresses: Vec::new(),
+            checkpoints: Vec::new(),
+        };
+        (stub, payment)
+    }
+
+    // The Miner's segwit spend of the reward, paying the Trader with change
+    fn segwit_spend(reward: Txid, fee: Amount) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(reward, 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::from_slice(&[vec![0x30; 71], vec![0x02; 33]]),
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_int_btc(20),
+                    script_pubkey: address(2).script_pubkey(),
+                },
+                TxOut {
+                    value: Amount::from_int_btc(30) - fee,
+                    script_pubkey: address(3).script_pubkey(),
+                },
+            ],
+        }
+    }
+
+    // The same payment as a pre-segwit transaction, which serializes without
+    // the marker and flag bytes
+    fn legacy_spend(reward: Txid, fee: Amount) -> Transaction {
+        let mut spend = segwit_spend(reward, fee);
+        let script_sig = [[0x47].as_slice(), &[0x30; 71], &[0x21], &[0x02; 33]].concat();
+        spend.input[0].script_sig = ScriptBuf::from_bytes(script_sig);
+        spend.input[0].witness = Witness::new();
+        spend
+    }
+
+    fn segwit_report() -> Report {
+        let fee = Amount::from_sat(1410);
+        let reward = coinbase(1, Amount::from_int_btc(50), &address(1));
+        let spend = segwit_spend(reward.txid(), fee);
+        let (stub, payment) = fixture(reward, spend, fee);
+        let report = build_report(&stub, &stub, payment, &Options::default()).unwrap();
+        Report::new(report, 1_700_000_000)
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Wire {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(Vec<u8>),
+    }
+
+    // Field number and value of every top-level field of a protobuf message,
+    // in wire order
+    fn decode_fields(mut bytes: &[u8]) -> Vec<(u64, Wire)> {
+        fn varint(bytes: &mut &[u8]) -> u64 {
+            let (mut value, mut shift) = (0, 0);
+            loop {
+                let byte = bytes[0];
+                *bytes = &bytes[1..];
+                value |= u64::from(byte & 0x7f) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return value;
+                }
+            }
+        }
+        let mut fields = Vec::new();
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            let value = match key & 7 {
+                0 => Wire::Varint(varint(&mut bytes)),
+                1 => {
+                    let (value, rest) = bytes.split_at(8);
+                    bytes = rest;
+                    Wire::Fixed64(u64::from_le_bytes(value.try_into().unwrap()))
+                }
+                2 => {
+                    let len = varint(&mut bytes) as usize;
+                    let (value, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    Wire::Bytes(value.to_vec())
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    // Field numbers of `message` in proto/tx_report.proto, by field name
+    fn proto_fields(message: &str) -> BTreeMap<String, u64> {
+        let proto = include_str!("../proto/tx_report.proto");
+        let body = proto
+            .split(&format!("message {message} {{"))
+            .nth(1)
+            .and_then(|rest| rest.split('}').next())
+            .unwrap();
+        body.lines()
+            .filter_map(|line| {
+                let (declaration, number) = line.split_once('=')?;
+                let name = declaration.split_whitespace().last()?;
+                let number = number.trim().trim_end_matches(';').parse().ok()?;
+                Some((name.to_string(), number))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn to_protobuf_follows_the_proto_file() {
+        let report = segwit_report();
+        let numbers = proto_fields("TxReport");
+        let fields: BTreeMap<u64, Wire> =
+            decode_fields(&report.to_protobuf()).into_iter().collect();
+        let field = |name: &str| &fields[&numbers[name]];
+        let string = |s: String| Wire::Bytes(s.into_bytes());
+        let double = |amount: Amount| Wire::Fixed64(amount.to_btc().to_bits());
+
+        assert_eq!(field("txid"), &string(report.txid.to_string()));
+        assert_eq!(
+            field("miner_input_address"),
+            &string(report.miner_input_address.to_string())
+        );
+        assert_eq!(
+            field("miner_input_amount"),
+            &double(report.miner_input_sats)
+        );
+        assert_eq!(
+            field("trader_output_address"),
+            &string(report.trader_output_address.to_string())
+        );
+        assert_eq!(
+            field("trader_output_amount"),
+            &double(report.trader_output_sats)
+        );
+        assert_eq!(
+            field("change_address"),
+            &string(report.change_address.as_ref().unwrap().to_string())
+        );
+        assert_eq!(field("change_amount"), &double(report.change_sats));
+        assert_eq!(field("fee"), &double(report.fee_sats));
+        assert_eq!(field("block_height"), &Wire::Varint(102));
+        assert_eq!(field("block_hash"), &string(report.block_hash.to_string()));
+        assert_eq!(field("input_count"), &Wire::Varint(1));
+        assert_eq!(field("vsize"), &Wire::Varint(report.vsize));
+        assert_eq!(
+            field("schema_version"),
+            &Wire::Varint(u64::from(REPORT_SCHEMA_VERSION))
+        );
+        // Nothing goes out under a number the proto file doesn't define
+        for number in fields.keys() {
+            assert!(numbers.values().any(|n| n == number), "field {number}");
+        }
+    }
+
+    #[test]
+    fn csv_line_quotes_only_what_needs_it() {
+        let fields = ["plain", "a,b", "say \"hi\"", "two\nlines", ""].map(String::from);
+        assert_eq!(
+            csv_line(&fields),
+            "plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\","
+        );
+    }
+
+    #[test]
+    fn out_txt_has_the_ten_autograder_lines() {
+        let report = segwit_report();
+        let expected = [
+            "661bf1fb2375aae2c3bbbaef608bacb72910e2fc00abef7b6d0b97d8ebd2f457",
+            "bcrt1qqyqszqgpqyqszqgpqyqszqgpqyqszqgpvxat9t",
+            "20",
+            "bcrt1qqgpqyqszqgpqyqszqgpqyqszqgpqyqszazmwwa",
+            "20",
+            "bcrt1qqvpsxqcrqvpsxqcrqvpsxqcrqvpsxqcruj60yu",
+            "29.9999859",
+            "0.0000141",
+            "102",
+            "5356b5d72ea833bb1df3cfb971ba634deb4dd55c8eb690be6d27ad765c154ca5",
+        ];
+        assert_eq!(report.out_txt(), expected.join("\n"));
+    }
+
+    #[test]
+    fn legacy_transaction_decodes_with_its_size_and_fee() {
+        let fee = Amount::from_sat(1410);
+        let reward = coinbase(1, Amount::from_int_btc(50), &legacy_address(1));
+        let spend = legacy_spend(reward.txid(), fee);
+        let (stub, payment) = fixture(reward, spend, fee);
+        let report = build_report(&stub, &stub, payment, &Options::default()).unwrap();
+        let report = Report::new(report, 1_700_000_000);
+        // Without witness data a vbyte is a byte: 4 version, 1 + 147 for the
+        // input, 1 + 2 * 31 for the outputs, 4 locktime
+        assert_eq!(report.vsize, 219);
+        assert_eq!(report.fee_sats, fee);
+        assert_eq!(report.fee_rate, 1410.0 / 219.0);
+        assert_eq!(report.change_sats, Amount::from_sat(2_999_998_590));
+        assert_eq!(report.input_count, 1);
+    }
+}
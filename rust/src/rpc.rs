@@ -0,0 +1,716 @@
+// The node RPC calls the capstone flow makes, as a trait of their own. The
+// helpers in the wallet, mining, transaction, report and psbt modules take
+// `&impl BitcoinRpc` rather than a concrete `Client`, so they can run against a
+// stub that answers with canned responses.
+//
+// The signatures mirror `bitcoincore_rpc::RpcApi`. Calls without a typed
+// wrapper go through `call`, just like they do on `RpcApi`.
+
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, Block, BlockHash, Transaction, Txid};
+use bitcoincore_rpc::json;
+use bitcoincore_rpc::{Client, Error, RawTx, Result, RpcApi};
+use serde::Deserialize;
+use serde_json::value::to_raw_value;
+use std::collections::HashMap;
+
+pub trait BitcoinRpc {
+    fn call<T: for<'a> Deserialize<'a>>(&self, cmd: &str, args: &[serde_json::Value]) -> Result<T>;
+    // The same `cmd` once per entry of `args`, sent to the node as a single
+    // JSON-RPC batch. Results come back in the order of `args`.
+    fn call_batch<T: for<'a> Deserialize<'a>>(
+        &self,
+        cmd: &str,
+        args: &[Vec<serde_json::Value>],
+    ) -> Result<Vec<T>>;
+
+    // Chain
+    fn get_blockchain_info(&self) -> Result<json::GetBlockchainInfoResult>;
+    fn get_block_count(&self) -> Result<u64>;
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash>;
+    fn get_block_header_info(&self, hash: &BlockHash) -> Result<json::GetBlockHeaderResult>;
+    fn get_block(&self, hash: &BlockHash) -> Result<Block>;
+    fn get_block_info(&self, hash: &BlockHash) -> Result<json::GetBlockResult>;
+    fn generate_to_address(&self, block_num: u64, address: &Address) -> Result<Vec<BlockHash>>;
+    fn scan_tx_out_set_blocking(
+        &self,
+        descriptors: &[json::ScanTxOutRequest],
+    ) -> Result<json::ScanTxOutResult>;
+
+    // Mempool and network
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<json::GetMempoolEntryResult>;
+    fn get_mempool_info(&self) -> Result<json::GetMempoolInfoResult>;
+    fn get_network_info(&self) -> Result<json::GetNetworkInfoResult>;
+    fn test_mempool_accept<R: RawTx>(
+        &self,
+        rawtxs: &[R],
+    ) -> Result<Vec<json::TestMempoolAcceptResult>>;
+
+    // Transactions
+    fn get_raw_transaction(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Transaction>;
+    fn get_raw_transaction_info(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<json::GetRawTransactionResult>;
+    fn decode_raw_transaction<R: RawTx>(
+        &self,
+        tx: R,
+        is_witness: Option<bool>,
+    ) -> Result<json::DecodeRawTransactionResult>;
+    fn create_raw_transaction_hex(
+        &self,
+        utxos: &[json::CreateRawTransactionInput],
+        outs: &HashMap<String, Amount>,
+        locktime: Option<i64>,
+        replaceable: Option<bool>,
+    ) -> Result<String>;
+    fn send_raw_transaction<R: RawTx>(&self, tx: R) -> Result<Txid>;
+
+    // Wallet
+    fn list_wallets(&self) -> Result<Vec<String>>;
+    fn load_wallet(&self, wallet: &str) -> Result<json::LoadWalletResult>;
+    fn create_wallet(
+        &self,
+        wallet: &str,
+        disable_private_keys: Option<bool>,
+        blank: Option<bool>,
+        passphrase: Option<&str>,
+        avoid_reuse: Option<bool>,
+    ) -> Result<json::LoadWalletResult>;
+    fn get_wallet_info(&self) -> Result<json::GetWalletInfoResult>;
+    fn get_new_address(
+        &self,
+        label: Option<&str>,
+        address_type: Option<json::AddressType>,
+    ) -> Result<Address<NetworkUnchecked>>;
+    fn get_address_info(&self, address: &Address) -> Result<json::GetAddressInfoResult>;
+    fn get_balance(
+        &self,
+        minconf: Option<usize>,
+        include_watchonly: Option<bool>,
+    ) -> Result<Amount>;
+    fn get_balances(&self) -> Result<json::GetBalancesResult>;
+    fn get_received_by_address(&self, address: &Address, minconf: Option<u32>) -> Result<Amount>;
+    fn list_transactions(
+        &self,
+        label: Option<&str>,
+        count: Option<usize>,
+        skip: Option<usize>,
+        include_watchonly: Option<bool>,
+    ) -> Result<Vec<json::ListTransactionResult>>;
+    fn get_transaction(
+        &self,
+        txid: &Txid,
+        include_watchonly: Option<bool>,
+    ) -> Result<json::GetTransactionResult>;
+    fn sign_raw_transaction_with_wallet<R: RawTx>(
+        &self,
+        tx: R,
+        utxos: Option<&[json::SignRawTransactionInput]>,
+        sighash_type: Option<json::SigHashType>,
+    ) -> Result<json::SignRawTransactionResult>;
+    fn key_pool_refill(&self, new_size: Option<usize>) -> Result<()>;
+    fn list_since_block(
+        &self,
+        blockhash: Option<&BlockHash>,
+        target_confirmations: Option<usize>,
+        include_watchonly: Option<bool>,
+        include_removed: Option<bool>,
+    ) -> Result<json::ListSinceBlockResult>;
+
+    // Descriptors
+    fn get_descriptor_info(&self, desc: &str) -> Result<json::GetDescriptorInfoResult>;
+    fn derive_addresses(
+        &self,
+        descriptor: &str,
+        range: Option<[u32; 2]>,
+    ) -> Result<Vec<Address<NetworkUnchecked>>>;
+    fn import_descriptors(
+        &self,
+        req: json::ImportDescriptors,
+    ) -> Result<Vec<json::ImportMultiResult>>;
+}
+
+impl BitcoinRpc for Client {
+    fn call<T: for<'a> Deserialize<'a>>(&self, cmd: &str, args: &[serde_json::Value]) -> Result<T> {
+        RpcApi::call(self, cmd, args)
+    }
+
+    fn call_batch<T: for<'a> Deserialize<'a>>(
+        &self,
+        cmd: &str,
+        args: &[Vec<serde_json::Value>],
+    ) -> Result<Vec<T>> {
+        if args.is_empty() {
+            return Ok(Vec::new());
+        }
+        let params = args
+            .iter()
+            .map(|args| args.iter().map(to_raw_value).collect())
+            .collect::<std::result::Result<Vec<Vec<_>>, _>>()?;
+        let client = self.get_jsonrpc_client();
+        let requests: Vec<_> = params
+            .iter()
+            .map(|params| client.build_request(cmd, params))
+            .collect();
+        client
+            .send_batch(&requests)?
+            .into_iter()
+            .map(|response| match response {
+                Some(response) => Ok(response.result()?),
+                None => Err(Error::ReturnedError(format!(
+                    "No response to one of the {cmd} calls in a batch"
+                ))),
+            })
+            .collect()
+    }
+
+    fn get_blockchain_info(&self) -> Result<json::GetBlockchainInfoResult> {
+        RpcApi::get_blockchain_info(self)
+    }
+
+    fn get_block_count(&self) -> Result<u64> {
+        RpcApi::get_block_count(self)
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash> {
+        RpcApi::get_block_hash(self, height)
+    }
+
+    fn get_block_header_info(&self, hash: &BlockHash) -> Result<json::GetBlockHeaderResult> {
+        RpcApi::get_block_header_info(self, hash)
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Block> {
+        RpcApi::get_block(self, hash)
+    }
+
+    fn get_block_info(&self, hash: &BlockHash) -> Result<json::GetBlockResult> {
+        RpcApi::get_block_info(self, hash)
+    }
+
+    fn generate_to_address(&self, block_num: u64, address: &Address) -> Result<Vec<BlockHash>> {
+        RpcApi::generate_to_address(self, block_num, address)
+    }
+
+    fn scan_tx_out_set_blocking(
+        &self,
+        descriptors: &[json::ScanTxOutRequest],
+    ) -> Result<json::ScanTxOutResult> {
+        RpcApi::scan_tx_out_set_blocking(self, descriptors)
+    }
+
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<json::GetMempoolEntryResult> {
+        RpcApi::get_mempool_entry(self, txid)
+    }
+
+    fn get_mempool_info(&self) -> Result<json::GetMempoolInfoResult> {
+        RpcApi::get_mempool_info(self)
+    }
+
+    fn get_network_info(&self) -> Result<json::GetNetworkInfoResult> {
+        RpcApi::get_network_info(self)
+    }
+
+    fn test_mempool_accept<R: RawTx>(
+        &self,
+        rawtxs: &[R],
+    ) -> Result<Vec<json::TestMempoolAcceptResult>> {
+        RpcApi::test_mempool_accept(self, rawtxs)
+    }
+
+    fn get_raw_transaction(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Transaction> {
+        RpcApi::get_raw_transaction(self, txid, block_hash)
+    }
+
+    fn get_raw_transaction_info(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<json::GetRawTransactionResult> {
+        RpcApi::get_raw_transaction_info(self, txid, block_hash)
+    }
+
+    fn decode_raw_transaction<R: RawTx>(
+        &self,
+        tx: R,
+        is_witness: Option<bool>,
+    ) -> Result<json::DecodeRawTransactionResult> {
+        RpcApi::decode_raw_transaction(self, tx, is_witness)
+    }
+
+    fn create_raw_transaction_hex(
+        &self,
+        utxos: &[json::CreateRawTransactionInput],
+        outs: &HashMap<String, Amount>,
+        locktime: Option<i64>,
+        replaceable: Option<bool>,
+    ) -> Result<String> {
+        RpcApi::create_raw_transaction_hex(self, utxos, outs, locktime, replaceable)
+    }
+
+    fn send_raw_transaction<R: RawTx>(&self, tx: R) -> Result<Txid> {
+        RpcApi::send_raw_transaction(self, tx)
+    }
+
+    fn list_wallets(&self) -> Result<Vec<String>> {
+        RpcApi::list_wallets(self)
+    }
+
+    fn load_wallet(&self, wallet: &str) -> Result<json::LoadWalletResult> {
+        RpcApi::load_wallet(self, wallet)
+    }
+
+    fn create_wallet(
+        &self,
+        wallet: &str,
+        disable_private_keys: Option<bool>,
+        blank: Option<bool>,
+        passphrase: Option<&str>,
+        avoid_reuse: Option<bool>,
+    ) -> Result<json::LoadWalletResult> {
+        RpcApi::create_wallet(
+            self,
+            wallet,
+            disable_private_keys,
+            blank,
+            passphrase,
+            avoid_reuse,
+        )
+    }
+
+    fn get_wallet_info(&self) -> Result<json::GetWalletInfoResult> {
+        RpcApi::get_wallet_info(self)
+    }
+
+    fn get_new_address(
+        &self,
+        label: Option<&str>,
+        address_type: Option<json::AddressType>,
+    ) -> Result<Address<NetworkUnchecked>> {
+        RpcApi::get_new_address(self, label, address_type)
+    }
+
+    fn get_address_info(&self, address: &Address) -> Result<json::GetAddressInfoResult> {
+        RpcApi::get_address_info(self, address)
+    }
+
+    fn get_balance(
+        &self,
+        minconf: Option<usize>,
+        include_watchonly: Option<bool>,
+    ) -> Result<Amount> {
+        RpcApi::get_balance(self, minconf, include_watchonly)
+    }
+
+    fn get_balances(&self) -> Result<json::GetBalancesResult> {
+        RpcApi::get_balances(self)
+    }
+
+    fn get_received_by_address(&self, address: &Address, minconf: Option<u32>) -> Result<Amount> {
+        RpcApi::get_received_by_address(self, address, minconf)
+    }
+
+    fn list_transactions(
+        &self,
+        label: Option<&str>,
+        count: Option<usize>,
+        skip: Option<usize>,
+        include_watchonly: Option<bool>,
+    ) -> Result<Vec<json::ListTransactionResult>> {
+        RpcApi::list_transactions(self, label, count, skip, include_watchonly)
+    }
+
+    fn get_transaction(
+        &self,
+        txid: &Txid,
+        include_watchonly: Option<bool>,
+    ) -> Result<json::GetTransactionResult> {
+        RpcApi::get_transaction(self, txid, include_watchonly)
+    }
+
+    fn sign_raw_transaction_with_wallet<R: RawTx>(
+        &self,
+        tx: R,
+        utxos: Option<&[json::SignRawTransactionInput]>,
+        sighash_type: Option<json::SigHashType>,
+    ) -> Result<json::SignRawTransactionResult> {
+        RpcApi::sign_raw_transaction_with_wallet(self, tx, utxos, sighash_type)
+    }
+
+    fn key_pool_refill(&self, new_size: Option<usize>) -> Result<()> {
+        RpcApi::key_pool_refill(self, new_size)
+    }
+
+    fn list_since_block(
+        &self,
+        blockhash: Option<&BlockHash>,
+        target_confirmations: Option<usize>,
+        include_watchonly: Option<bool>,
+        include_removed: Option<bool>,
+    ) -> Result<json::ListSinceBlockResult> {
+        RpcApi::list_since_block(
+            self,
+            blockhash,
+            target_confirmations,
+            include_watchonly,
+            include_removed,
+        )
+    }
+
+    fn get_descriptor_info(&self, desc: &str) -> Result<json::GetDescriptorInfoResult> {
+        RpcApi::get_descriptor_info(self, desc)
+    }
+
+    fn derive_addresses(
+        &self,
+        descriptor: &str,
+        range: Option<[u32; 2]>,
+    ) -> Result<Vec<Address<NetworkUnchecked>>> {
+        RpcApi::derive_addresses(self, descriptor, range)
+    }
+
+    fn import_descriptors(
+        &self,
+        req: json::ImportDescriptors,
+    ) -> Result<Vec<json::ImportMultiResult>> {
+        RpcApi::import_descriptors(self, req)
+    }
+}
+
+// A `BitcoinRpc` that answers from canned JSON, for tests. A request is looked
+// up as its method and arguments joined by spaces, then again with trailing
+// arguments dropped one at a time, so "gettransaction <txid>" answers the call
+// whatever include_watchonly is. Calls nothing was canned for fail.
+#[cfg(test)]
+pub mod stub {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::consensus::encode;
+    use bitcoincore_rpc::bitcoin::hashes::hex::FromHex;
+    use serde_json::{json, Value};
+
+    #[derive(Default)]
+    pub struct StubRpc {
+        responses: HashMap<String, Value>,
+    }
+
+    impl StubRpc {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn respond(mut self, request: &str, response: Value) -> Self {
+            self.responses.insert(request.to_string(), response);
+            self
+        }
+
+        fn response(&self, cmd: &str, args: &[Value]) -> Result<Value> {
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| match arg {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect();
+            (0..=args.len())
+                .rev()
+                .map(|n| {
+                    std::iter::once(cmd)
+                        .chain(args[..n].iter().map(String::as_str))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .find_map(|request| self.responses.get(&request).cloned())
+                .ok_or_else(|| {
+                    Error::ReturnedError(format!("No canned response to {cmd} {}", args.join(" ")))
+                })
+        }
+
+        // Calls whose result is a consensus-encoded object in hex
+        fn decode_hex<T: encode::Decodable>(&self, cmd: &str, args: &[Value]) -> Result<T> {
+            let hex: String = self.call(cmd, args)?;
+            let bytes = Vec::<u8>::from_hex(&hex)
+                .map_err(|e| Error::ReturnedError(format!("Canned {cmd} is not hex: {e}")))?;
+            Ok(encode::deserialize(&bytes)?)
+        }
+    }
+
+    impl BitcoinRpc for StubRpc {
+        fn call<T: for<'a> Deserialize<'a>>(&self, cmd: &str, args: &[Value]) -> Result<T> {
+            Ok(serde_json::from_value(self.response(cmd, args)?)?)
+        }
+
+        fn call_batch<T: for<'a> Deserialize<'a>>(
+            &self,
+            cmd: &str,
+            args: &[Vec<Value>],
+        ) -> Result<Vec<T>> {
+            args.iter().map(|args| self.call(cmd, args)).collect()
+        }
+
+        fn get_blockchain_info(&self) -> Result<json::GetBlockchainInfoResult> {
+            self.call("getblockchaininfo", &[])
+        }
+
+        fn get_block_count(&self) -> Result<u64> {
+            self.call("getblockcount", &[])
+        }
+
+        fn get_block_hash(&self, height: u64) -> Result<BlockHash> {
+            self.call("getblockhash", &[json!(height)])
+        }
+
+        fn get_block_header_info(&self, hash: &BlockHash) -> Result<json::GetBlockHeaderResult> {
+            self.call("getblockheader", &[json!(hash)])
+        }
+
+        fn get_block(&self, hash: &BlockHash) -> Result<Block> {
+            self.decode_hex("getblock", &[json!(hash), json!(0)])
+        }
+
+        fn get_block_info(&self, hash: &BlockHash) -> Result<json::GetBlockResult> {
+            self.call("getblock", &[json!(hash), json!(1)])
+        }
+
+        fn generate_to_address(&self, block_num: u64, address: &Address) -> Result<Vec<BlockHash>> {
+            self.call("generatetoaddress", &[json!(block_num), json!(address)])
+        }
+
+        fn scan_tx_out_set_blocking(
+            &self,
+            descriptors: &[json::ScanTxOutRequest],
+        ) -> Result<json::ScanTxOutResult> {
+            self.call("scantxoutset", &[json!("start"), json!(descriptors)])
+        }
+
+        fn get_mempool_entry(&self, txid: &Txid) -> Result<json::GetMempoolEntryResult> {
+            self.call("getmempoolentry", &[json!(txid)])
+        }
+
+        fn get_mempool_info(&self) -> Result<json::GetMempoolInfoResult> {
+            self.call("getmempoolinfo", &[])
+        }
+
+        fn get_network_info(&self) -> Result<json::GetNetworkInfoResult> {
+            self.call("getnetworkinfo", &[])
+        }
+
+        fn test_mempool_accept<R: RawTx>(
+            &self,
+            rawtxs: &[R],
+        ) -> Result<Vec<json::TestMempoolAcceptResult>> {
+            let hexes: Vec<_> = rawtxs.iter().cloned().map(RawTx::raw_hex).collect();
+            self.call("testmempoolaccept", &[json!(hexes)])
+        }
+
+        fn get_raw_transaction(
+            &self,
+            txid: &Txid,
+            block_hash: Option<&BlockHash>,
+        ) -> Result<Transaction> {
+            self.decode_hex(
+                "getrawtransaction",
+                &[json!(txid), json!(false), json!(block_hash)],
+            )
+        }
+
+        fn get_raw_transaction_info(
+            &self,
+            txid: &Txid,
+            block_hash: Option<&BlockHash>,
+        ) -> Result<json::GetRawTransactionResult> {
+            self.call(
+                "getrawtransaction",
+                &[json!(txid), json!(true), json!(block_hash)],
+            )
+        }
+
+        fn decode_raw_transaction<R: RawTx>(
+            &self,
+            tx: R,
+            is_witness: Option<bool>,
+        ) -> Result<json::DecodeRawTransactionResult> {
+            self.call(
+                "decoderawtransaction",
+                &[json!(tx.raw_hex()), json!(is_witness)],
+            )
+        }
+
+        fn create_raw_transaction_hex(
+            &self,
+            utxos: &[json::CreateRawTransactionInput],
+            outs: &HashMap<String, Amount>,
+            locktime: Option<i64>,
+            replaceable: Option<bool>,
+        ) -> Result<String> {
+            let outs: HashMap<_, _> = outs.iter().map(|(k, v)| (k, v.to_btc())).collect();
+            self.call(
+                "createrawtransaction",
+                &[
+                    json!(utxos),
+                    json!(outs),
+                    json!(locktime),
+                    json!(replaceable),
+                ],
+            )
+        }
+
+        fn send_raw_transaction<R: RawTx>(&self, tx: R) -> Result<Txid> {
+            self.call("sendrawtransaction", &[json!(tx.raw_hex())])
+        }
+
+        fn list_wallets(&self) -> Result<Vec<String>> {
+            self.call("listwallets", &[])
+        }
+
+        fn load_wallet(&self, wallet: &str) -> Result<json::LoadWalletResult> {
+            self.call("loadwallet", &[json!(wallet)])
+        }
+
+        fn create_wallet(
+            &self,
+            wallet: &str,
+            disable_private_keys: Option<bool>,
+            blank: Option<bool>,
+            passphrase: Option<&str>,
+            avoid_reuse: Option<bool>,
+        ) -> Result<json::LoadWalletResult> {
+            let args = [
+                json!(wallet),
+                json!(disable_private_keys),
+                json!(blank),
+                json!(passphrase),
+                json!(avoid_reuse),
+            ];
+            self.call("createwallet", &args)
+        }
+
+        fn get_wallet_info(&self) -> Result<json::GetWalletInfoResult> {
+            self.call("getwalletinfo", &[])
+        }
+
+        fn get_new_address(
+            &self,
+            label: Option<&str>,
+            address_type: Option<json::AddressType>,
+        ) -> Result<Address<NetworkUnchecked>> {
+            self.call("getnewaddress", &[json!(label), json!(address_type)])
+        }
+
+        fn get_address_info(&self, address: &Address) -> Result<json::GetAddressInfoResult> {
+            self.call("getaddressinfo", &[json!(address)])
+        }
+
+        fn get_balance(
+            &self,
+            minconf: Option<usize>,
+            include_watchonly: Option<bool>,
+        ) -> Result<Amount> {
+            let balance: f64 = self.call(
+                "getbalance",
+                &[json!("*"), json!(minconf), json!(include_watchonly)],
+            )?;
+            Amount::from_btc(balance)
+                .map_err(|e| Error::ReturnedError(format!("Canned getbalance: {e}")))
+        }
+
+        fn get_balances(&self) -> Result<json::GetBalancesResult> {
+            self.call("getbalances", &[])
+        }
+
+        fn get_received_by_address(
+            &self,
+            address: &Address,
+            minconf: Option<u32>,
+        ) -> Result<Amount> {
+            let received: f64 =
+                self.call("getreceivedbyaddress", &[json!(address), json!(minconf)])?;
+            Amount::from_btc(received)
+                .map_err(|e| Error::ReturnedError(format!("Canned getreceivedbyaddress: {e}")))
+        }
+
+        fn list_transactions(
+            &self,
+            label: Option<&str>,
+            count: Option<usize>,
+            skip: Option<usize>,
+            include_watchonly: Option<bool>,
+        ) -> Result<Vec<json::ListTransactionResult>> {
+            let args = [
+                json!(label),
+                json!(count),
+                json!(skip),
+                json!(include_watchonly),
+            ];
+            self.call("listtransactions", &args)
+        }
+
+        fn get_transaction(
+            &self,
+            txid: &Txid,
+            include_watchonly: Option<bool>,
+        ) -> Result<json::GetTransactionResult> {
+            self.call("gettransaction", &[json!(txid), json!(include_watchonly)])
+        }
+
+        fn sign_raw_transaction_with_wallet<R: RawTx>(
+            &self,
+            tx: R,
+            utxos: Option<&[json::SignRawTransactionInput]>,
+            sighash_type: Option<json::SigHashType>,
+        ) -> Result<json::SignRawTransactionResult> {
+            self.call(
+                "signrawtransactionwithwallet",
+                &[json!(tx.raw_hex()), json!(utxos), json!(sighash_type)],
+            )
+        }
+
+        fn key_pool_refill(&self, new_size: Option<usize>) -> Result<()> {
+            self.call("keypoolrefill", &[json!(new_size)])
+        }
+
+        fn list_since_block(
+            &self,
+            blockhash: Option<&BlockHash>,
+            target_confirmations: Option<usize>,
+            include_watchonly: Option<bool>,
+            include_removed: Option<bool>,
+        ) -> Result<json::ListSinceBlockResult> {
+            let args = [
+                json!(blockhash),
+                json!(target_confirmations),
+                json!(include_watchonly),
+                json!(include_removed),
+            ];
+            self.call("listsinceblock", &args)
+        }
+
+        fn get_descriptor_info(&self, desc: &str) -> Result<json::GetDescriptorInfoResult> {
+            self.call("getdescriptorinfo", &[json!(desc)])
+        }
+
+        fn derive_addresses(
+            &self,
+            descriptor: &str,
+            range: Option<[u32; 2]>,
+        ) -> Result<Vec<Address<NetworkUnchecked>>> {
+            self.call("deriveaddresses", &[json!(descriptor), json!(range)])
+        }
+
+        fn import_descriptors(
+            &self,
+            req: json::ImportDescriptors,
+        ) -> Result<Vec<json::ImportMultiResult>> {
+            self.call("importdescriptors", &[json!([req])])
+        }
+    }
+}
@@ -0,0 +1,1235 @@
+// Funding, checking and sending the payment, and tracing where its coins
+// came from.
+
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::amount::serde::{as_btc, as_sat};
+use bitcoincore_rpc::bitcoin::hex::DisplayHex;
+use bitcoincore_rpc::bitcoin::{Address, Amount, Network, Transaction, TxOut, Txid};
+use bitcoincore_rpc::json::EstimateMode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::mining::*;
+use crate::psbt::{finalize_psbt, sign_psbt};
+use crate::*;
+
+// How far (relative) the achieved fee rate may be from --fee-rate without a warning
+pub const FEE_RATE_TOLERANCE: f64 = 0.05;
+
+// Bitcoin Core's default -dustrelayfee, per 1000 vbytes
+pub const DUST_RELAY_FEE: Amount = Amount::from_sat(3000);
+
+// Node-wide view of the mempool, as context for the single entry we look at
+pub fn show_mempool_info(
+    rpc: &impl rpc::BitcoinRpc,
+    format: &Format,
+) -> bitcoincore_rpc::Result<()> {
+    let info = rpc.get_mempool_info()?;
+    if *format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    status!("Mempool transactions: {}", info.size);
+    status!("Mempool size: {} vbytes", info.bytes);
+    status!("Mempool memory usage: {} bytes", info.usage);
+    match info.total_fee {
+        Some(total_fee) => status!("Total fees: {} BTC", total_fee.to_btc()),
+        None => status!("Total fees: unknown"),
+    }
+    status!(
+        "Minimum relay fee: {} BTC/kvB",
+        info.min_relay_tx_fee.to_btc()
+    );
+    Ok(())
+}
+
+// What the Miner wallet knows about one of its transactions, for `report <txid>`
+pub fn print_wallet_transaction(
+    miner: &impl rpc::BitcoinRpc,
+    txid: &Txid,
+) -> bitcoincore_rpc::Result<()> {
+    let tx = miner.get_transaction(txid, None)?;
+    status!("Transaction: {txid}");
+    status!("Amount: {} BTC", tx.amount.to_btc());
+    if let Some(fee) = tx.fee {
+        status!("Fee: {} BTC", fee.to_btc());
+    }
+    status!("Confirmations: {}", tx.info.confirmations);
+    if let (Some(hash), Some(height)) = (tx.info.blockhash, tx.info.blockheight) {
+        status!("Block: {height} ({hash})");
+    }
+    for detail in &tx.details {
+        if let Some(address) = &detail.address {
+            status!(
+                "  {:?} {} BTC {}",
+                detail.category,
+                detail.amount.to_btc(),
+                address.clone().assume_checked()
+            );
+        }
+    }
+    Ok(())
+}
+
+// A coin spent by our transaction and where it came from
+#[derive(Serialize)]
+pub struct FundingSource {
+    pub txid: Txid,
+    pub vout: u32,
+    #[serde(rename = "amount_sats", with = "as_sat")]
+    pub amount: Amount,
+    // Height of the block that mined it, when the coin is a block reward
+    pub coinbase_height: Option<u64>,
+    // Height of the block that created the coin, if confirmed
+    pub created_height: Option<u64>,
+}
+
+// Follow each input of `tx` back to the transaction that created the coin it
+// spends, noting the mining height of coinbase rewards. The previous
+// transactions and their blocks are fetched in one batch each, a payment
+// funded by many small rewards would otherwise cost two round trips per input.
+pub fn trace_funding(
+    rpc: &impl rpc::BitcoinRpc,
+    tx: &bitcoincore_rpc::json::DecodeRawTransactionResult,
+) -> bitcoincore_rpc::Result<Vec<FundingSource>> {
+    let inputs: Vec<(Txid, u32)> = tx
+        .vin
+        .iter()
+        .filter_map(|input| Some((input.txid?, input.vout?)))
+        .collect();
+    let args: Vec<_> = inputs
+        .iter()
+        .map(|(txid, _)| vec![json!(txid), json!(true)])
+        .collect();
+    let prevs: Vec<bitcoincore_rpc::json::GetRawTransactionResult> =
+        rpc.call_batch("getrawtransaction", &args)?;
+    let mut block_hashes: Vec<_> = prevs.iter().filter_map(|prev| prev.blockhash).collect();
+    block_hashes.sort();
+    block_hashes.dedup();
+    let heights = heights_for_hashes(rpc, &block_hashes)?;
+
+    let mut sources = Vec::new();
+    for ((txid, vout), prev) in inputs.into_iter().zip(prevs) {
+        let amount = prev
+            .vout
+            .iter()
+            .find(|out| out.n == vout)
+            .map_or(Amount::ZERO, |out| out.value);
+        let created_height = prev.blockhash.and_then(|hash| heights.get(&hash).copied());
+        let coinbase_height = created_height.filter(|_| prev.is_coinbase());
+        sources.push(FundingSource {
+            txid,
+            vout,
+            amount,
+            coinbase_height,
+            created_height,
+        });
+    }
+    Ok(sources)
+}
+
+// Compare the fee the wallet reports with the one the chain implies: what the
+// traced inputs were worth minus what the outputs pay out. Both are whole
+// satoshis, so any difference points at an accounting bug.
+pub fn reconcile_fee(
+    wallet_fee: Amount,
+    sources: &[FundingSource],
+    tx: &bitcoincore_rpc::json::DecodeRawTransactionResult,
+) {
+    let inputs: i64 = sources.iter().map(|s| s.amount.to_sat() as i64).sum();
+    let outputs: i64 = tx.vout.iter().map(|out| out.value.to_sat() as i64).sum();
+    let on_chain = inputs - outputs;
+    let wallet = wallet_fee.to_sat() as i64;
+    if on_chain != wallet {
+        status!(
+            "Warning: wallet reports a fee of {wallet} sat but inputs minus outputs is {on_chain} sat"
+        );
+    } else {
+        debug(&format!(
+            "Wallet fee matches inputs minus outputs: {on_chain} sat"
+        ));
+    }
+}
+
+// Check from the recipient's side that the payment arrived: the confirmed total
+// `address` has received must have grown by exactly `sent` since `before`.
+// Measured as a difference because a reused address (--state-file) already
+// holds earlier payments.
+pub fn verify_received(
+    wallet: &impl rpc::BitcoinRpc,
+    address: &Address,
+    before: Amount,
+    sent: Amount,
+) -> bitcoincore_rpc::Result<()> {
+    let after = wallet.get_received_by_address(address, None)?;
+    let received = after.checked_sub(before).unwrap_or(Amount::ZERO);
+    if received != sent {
+        status!(
+            "Warning: {address} received {} BTC but {} BTC was sent",
+            received.to_btc(),
+            sent.to_btc()
+        );
+    } else {
+        debug(&format!(
+            "Trader received the full {} BTC",
+            received.to_btc()
+        ));
+    }
+    Ok(())
+}
+
+// Coin days destroyed by spending `sources` at `spent_height`: each coin's
+// value times its age in blocks, summed. Old coins moving weigh more than
+// freshly received ones.
+pub fn coin_days_destroyed(sources: &[FundingSource], spent_height: u64) -> f64 {
+    sources
+        .iter()
+        .filter_map(|s| {
+            let created = s.created_height?;
+            Some(s.amount.to_btc() * spent_height.saturating_sub(created) as f64)
+        })
+        .sum()
+}
+
+// One entry of a JSON recipients file
+#[derive(Deserialize)]
+pub struct RecipientEntry {
+    pub address: String,
+    pub amount: serde_json::Number,
+}
+
+// Read recipients from a JSON array of `{address, amount}` objects or, when the
+// file ends in .csv, from `address,amount` lines with an optional header.
+// Checks every address is valid for `network` and every amount is a valid BTC
+// amount.
+pub fn load_recipients(
+    path: &str,
+    network: Network,
+) -> bitcoincore_rpc::Result<Vec<(Address, Amount)>> {
+    let contents = std::fs::read_to_string(path)?;
+    let entries: Vec<(String, String)> = if path.ends_with(".csv") {
+        recipients_csv(&contents)?
+    } else {
+        serde_json::from_str::<Vec<RecipientEntry>>(&contents)?
+            .into_iter()
+            .map(|entry| (entry.address, entry.amount.to_string()))
+            .collect()
+    };
+
+    let mut recipients = Vec::with_capacity(entries.len());
+    for (i, (address, amount)) in entries.into_iter().enumerate() {
+        let address = address
+            .parse::<Address<NetworkUnchecked>>()
+            .map_err(|e| {
+                bitcoincore_rpc::Error::ReturnedError(format!(
+                    "Recipient {i}: invalid address {address}: {e}"
+                ))
+            })?
+            .require_network(network)
+            .map_err(|e| bitcoincore_rpc::Error::ReturnedError(format!("Recipient {i}: {e}")))?;
+        let amount = parse_amount(&format!("recipient {i} amount"), Some(amount))?;
+        recipients.push((address, amount));
+    }
+    if recipients.is_empty() {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "{path} lists no recipients"
+        )));
+    }
+    Ok(recipients)
+}
+
+// The `address,amount` pairs of a recipients CSV. Blank lines and `#` comments
+// are skipped, and so is a first line whose amount column isn't an amount.
+fn recipients_csv(text: &str) -> bitcoincore_rpc::Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (address, amount) = line.split_once(',').ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError(format!(
+                "Recipients CSV line {}: expected address,amount",
+                number + 1
+            ))
+        })?;
+        let amount = amount.trim();
+        if entries.is_empty() && !amount.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            continue;
+        }
+        entries.push((address.trim().to_string(), amount.to_string()));
+    }
+    Ok(entries)
+}
+
+// Pay every recipient in one transaction through `send_payment`, then show
+// where each payment landed and what batching saved over paying them one by
+// one. The one-by-one fees are what the wallet would pick funding each payment
+// on its own right before the batch goes out.
+pub fn pay_many(
+    wallet: &impl rpc::BitcoinRpc,
+    recipients: &[(Address, Amount)],
+    options: &Options,
+) -> bitcoincore_rpc::Result<Txid> {
+    let mut individual_fees = Amount::ZERO;
+    for payee in recipients {
+        let funded = fund_payment(wallet, std::slice::from_ref(payee), options)?;
+        individual_fees += funded.fee;
+    }
+
+    let txid = send_payment(wallet, recipients, options)?;
+    status!("Paid {} recipients in transaction {txid}", recipients.len());
+
+    let decoded = wallet.decode_raw_transaction(&wallet.get_raw_transaction(&txid, None)?, None)?;
+    for (address, amount) in recipients {
+        let vout = decoded
+            .vout
+            .iter()
+            .find(|v| v.script_pub_key.address.as_ref() == Some(address.as_unchecked()))
+            .map_or("?".to_string(), |v| v.n.to_string());
+        status!("  Output {vout}: {} BTC to {address}", amount.to_btc());
+    }
+
+    let fee = wallet
+        .get_transaction(&txid, None)?
+        .fee
+        .and_then(|fee| fee.abs().to_unsigned().ok())
+        .unwrap_or(Amount::ZERO);
+    status!("Batch fee: {} BTC", fee.to_btc());
+    if let Some(requested) = options.fee_rate {
+        let entry = wallet.get_mempool_entry(&txid)?;
+        assert_fee_rate(
+            effective_fee_rates(&entry).individual,
+            requested,
+            FEE_RATE_TOLERANCE,
+        );
+    }
+    status!(
+        "Separate sends would have paid about {} BTC in fees",
+        individual_fees.to_btc()
+    );
+    match individual_fees.checked_sub(fee) {
+        Some(saved) => status!("Saved by batching: {} BTC", saved.to_btc()),
+        None => status!("Batching saved nothing this time"),
+    }
+    Ok(txid)
+}
+
+// Fee rates in sat/vB of a mempool entry: the transaction on its own, and the
+// package of it plus all its unconfirmed ancestors. Miners select by the latter,
+// so a cheap parent drags down the effective rate of a well-paying child.
+pub struct FeeRates {
+    pub individual: f64,
+    pub package: f64,
+}
+
+pub fn effective_fee_rates(entry: &bitcoincore_rpc::json::GetMempoolEntryResult) -> FeeRates {
+    FeeRates {
+        individual: entry.fees.base.to_sat() as f64 / entry.vsize as f64,
+        package: entry.fees.ancestor.to_sat() as f64 / entry.ancestor_size as f64,
+    }
+}
+
+pub fn print_fee_rates(entry: &bitcoincore_rpc::json::GetMempoolEntryResult) {
+    let rates = effective_fee_rates(entry);
+    status!(
+        "Fee rate: {:.2} sat/vB individual, {:.2} sat/vB including {} ancestor(s)",
+        rates.individual,
+        rates.package,
+        entry.ancestor_count - 1
+    );
+}
+
+// Ask the wallet to fund (but not sign or broadcast) the payment so we know the
+// fee it is going to pick before any coins leave the wallet.
+// Dust outputs make the transaction non-standard, so warn about them here too.
+pub fn preview_send_fee(
+    wallet: &impl rpc::BitcoinRpc,
+    payees: &[(Address, Amount)],
+    options: &Options,
+) -> bitcoincore_rpc::Result<Amount> {
+    let funded = fund_payment(wallet, payees, options)?;
+    if let Ok(tx) = funded.transaction() {
+        warn_dust_outputs(&tx, DUST_RELAY_FEE);
+    }
+    Ok(funded.fee)
+}
+
+// Whether `txout` is worth less than it costs to spend at `dust_relay_fee`
+// (per 1000 vbytes). Mirrors Bitcoin Core's GetDustThreshold: the output's own
+// size plus a typical input spending it, with the witness part discounted.
+pub fn is_dust(txout: &TxOut, dust_relay_fee: Amount) -> bool {
+    let script = &txout.script_pubkey;
+    if script.is_op_return() {
+        return false;
+    }
+    let spend_size = if script.is_witness_program() {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    let size = bitcoincore_rpc::bitcoin::consensus::serialize(txout).len() as u64 + spend_size;
+    txout.value.to_sat() < size * dust_relay_fee.to_sat() / 1000
+}
+
+pub fn warn_dust_outputs(tx: &Transaction, dust_relay_fee: Amount) {
+    for (vout, output) in tx.output.iter().enumerate() {
+        if is_dust(output, dust_relay_fee) {
+            status!(
+                "Warning: output {vout} of {} BTC is dust and won't be relayed",
+                output.value.to_btc()
+            );
+        }
+    }
+}
+
+// An unsigned transaction paying each of `payees`, funded by the wallet the
+// way a send with `options` would be. Nothing is locked or broadcast. With
+// --subtract-fee the payees' outputs share the fee instead of the wallet
+// adding it on top.
+pub fn fund_payment(
+    wallet: &impl rpc::BitcoinRpc,
+    payees: &[(Address, Amount)],
+    options: &Options,
+) -> bitcoincore_rpc::Result<bitcoincore_rpc::json::FundRawTransactionResult> {
+    let outputs: HashMap<String, Amount> = payees
+        .iter()
+        .map(|(address, amount)| (address.to_string(), *amount))
+        .collect();
+    let unfunded = wallet.create_raw_transaction_hex(&[], &outputs, None, None)?;
+    wallet.call(
+        "fundrawtransaction",
+        &[
+            json!(unfunded),
+            json!(funding_options(payees.len(), options)),
+        ],
+    )
+}
+
+// The options object `fundrawtransaction` and `walletcreatefundedpsbt` share,
+// for a transaction whose only outputs so far are `payee_count` payments. The
+// typed wrappers send `fee_rate` in BTC while the node reads it as sat/vB, so
+// build it ourselves.
+pub fn funding_options(
+    payee_count: usize,
+    options: &Options,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut fund_options = serde_json::Map::new();
+    if let Some(rate) = options.fee_rate {
+        fund_options.insert("fee_rate".to_string(), json!(rate));
+    }
+    if let Some(mode) = options.estimate_mode {
+        fund_options.insert("estimate_mode".to_string(), json!(mode));
+    }
+    fund_options.insert("replaceable".to_string(), json!(options.replaceable));
+    if options.subtract_fee {
+        let positions: Vec<usize> = (0..payee_count).collect();
+        fund_options.insert("subtractFeeFromOutputs".to_string(), json!(positions));
+    }
+    fund_options
+}
+
+// The payment with a fee of exactly `fee` (--fee), signed and ready to
+// broadcast. The wallet funds it as usual, then the difference to `fee` is
+// moved into or out of the change output.
+pub fn absolute_fee_transaction(
+    wallet: &impl rpc::BitcoinRpc,
+    payees: &[(Address, Amount)],
+    fee: Amount,
+    options: &Options,
+) -> bitcoincore_rpc::Result<Vec<u8>> {
+    let funded = fund_payment(wallet, payees, options)?;
+    let mut tx = funded.transaction().map_err(|e| {
+        bitcoincore_rpc::Error::ReturnedError(format!("Can't decode funded transaction: {e}"))
+    })?;
+    let Ok(change_position) = usize::try_from(funded.change_position) else {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "The wallet funded the payment without change, so --fee has nothing to adjust"
+                .to_string(),
+        ));
+    };
+    let change = &mut tx.output[change_position];
+    let adjusted = (change.value + funded.fee)
+        .checked_sub(fee)
+        .filter(|value| *value >= change.script_pubkey.dust_value())
+        .ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError(format!(
+                "A fee of {} BTC doesn't leave enough change in the funded transaction",
+                fee.to_btc()
+            ))
+        })?;
+    change.value = adjusted;
+
+    let signed = wallet.sign_raw_transaction_with_wallet(&tx, None, None)?;
+    if !signed.complete {
+        let unsigned = bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex(&tx);
+        return Err(incomplete_signing_error(wallet, &unsigned, signed)?);
+    }
+    Ok(signed.hex)
+}
+
+// What to tell the user when signrawtransactionwithwallet left `unsigned` only
+// partly signed: which inputs failed, and a PSBT of the transaction for the
+// other signers rather than the half-signed raw one
+fn incomplete_signing_error(
+    wallet: &impl rpc::BitcoinRpc,
+    unsigned: &str,
+    signed: bitcoincore_rpc::json::SignRawTransactionResult,
+) -> bitcoincore_rpc::Result<bitcoincore_rpc::Error> {
+    let psbt: String = wallet.call("converttopsbt", &[json!(unsigned)])?;
+    let errors: Vec<String> = signed
+        .errors
+        .unwrap_or_default()
+        .iter()
+        .map(|e| format!("input {}:{}: {}", e.txid, e.vout, e.error))
+        .collect();
+    Ok(bitcoincore_rpc::Error::ReturnedError(format!(
+        "The wallet could not fully sign the transaction ({}); \
+         PSBT needing further signatures: {psbt}",
+        errors.join(", ")
+    )))
+}
+
+// Why testmempoolaccept turned a transaction down, sorted into the cases worth
+// telling apart. The node's own reason string is kept for the rest.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MempoolRejection {
+    // Below the relay or mempool minimum, or too little to replace a conflict
+    FeeTooLow(String),
+    // Above the maxfeerate the node was asked to enforce
+    FeeTooHigh(String),
+    // Locktime or relative locktime (BIP 68) not reached yet
+    NonFinal(String),
+    // Spends an output an unconfirmed transaction already spends
+    Conflict(String),
+    // Spends an output that doesn't exist or is already spent in a block
+    MissingInputs(String),
+    Other(String),
+}
+
+impl MempoolRejection {
+    pub fn from_reason(reason: &str) -> Self {
+        let reason = reason.to_string();
+        if [
+            "min relay fee not met",
+            "mempool min fee not met",
+            "insufficient fee",
+        ]
+        .iter()
+        .any(|low| reason.contains(low))
+        {
+            MempoolRejection::FeeTooLow(reason)
+        } else if reason.contains("max-fee-exceeded") || reason.contains("absurdly-high-fee") {
+            MempoolRejection::FeeTooHigh(reason)
+        } else if reason.contains("non-final") || reason.contains("non-BIP68-final") {
+            MempoolRejection::NonFinal(reason)
+        } else if reason.contains("conflict") {
+            MempoolRejection::Conflict(reason)
+        } else if reason.contains("missingorspent") || reason.contains("missing-inputs") {
+            MempoolRejection::MissingInputs(reason)
+        } else {
+            MempoolRejection::Other(reason)
+        }
+    }
+}
+
+impl std::fmt::Display for MempoolRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MempoolRejection::FeeTooLow(reason) => write!(f, "fee rate too low ({reason})"),
+            MempoolRejection::FeeTooHigh(reason) => write!(f, "fee rate too high ({reason})"),
+            MempoolRejection::NonFinal(reason) => write!(f, "not final yet ({reason})"),
+            MempoolRejection::Conflict(reason) => {
+                write!(f, "conflicts with a mempool transaction ({reason})")
+            }
+            MempoolRejection::MissingInputs(reason) => {
+                write!(f, "inputs missing or already spent ({reason})")
+            }
+            MempoolRejection::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+// Whether the mempool would take `raw`, and if not why
+pub fn check_mempool_accept(
+    rpc: &impl rpc::BitcoinRpc,
+    raw: &[u8],
+) -> bitcoincore_rpc::Result<std::result::Result<(), MempoolRejection>> {
+    let result = rpc.test_mempool_accept(&[raw])?.pop().ok_or_else(|| {
+        bitcoincore_rpc::Error::ReturnedError("testmempoolaccept returned no result".to_string())
+    })?;
+    if result.allowed {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(MempoolRejection::from_reason(
+            &result.reject_reason.unwrap_or_default(),
+        )))
+    }
+}
+
+// Why `broadcast_checked` didn't broadcast: the mempool turned the transaction
+// down, or talking to the node failed
+#[derive(Debug)]
+pub enum BroadcastError {
+    Rejected(MempoolRejection),
+    Rpc(bitcoincore_rpc::Error),
+}
+
+impl std::fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BroadcastError::Rejected(rejection) => {
+                write!(f, "The mempool would reject the transaction: {rejection}")
+            }
+            BroadcastError::Rpc(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+impl From<bitcoincore_rpc::Error> for BroadcastError {
+    fn from(error: bitcoincore_rpc::Error) -> Self {
+        BroadcastError::Rpc(error)
+    }
+}
+
+// For callers that only report the error
+impl From<BroadcastError> for bitcoincore_rpc::Error {
+    fn from(error: BroadcastError) -> Self {
+        match error {
+            BroadcastError::Rpc(e) => e,
+            rejected => bitcoincore_rpc::Error::ReturnedError(rejected.to_string()),
+        }
+    }
+}
+
+// Broadcast a transaction we built ourselves, after testmempoolaccept had its
+// say, so a rejection names its cause instead of sendrawtransaction's bare
+// error code
+pub fn broadcast_checked(
+    rpc: &impl rpc::BitcoinRpc,
+    raw: &[u8],
+) -> std::result::Result<Txid, BroadcastError> {
+    if let Err(rejection) = check_mempool_accept(rpc, raw)? {
+        return Err(BroadcastError::Rejected(rejection));
+    }
+    Ok(rpc.send_raw_transaction(raw)?)
+}
+
+// Build and sign the payment, then ask the node whether its mempool would
+// accept it, without broadcasting (--test-accept)
+pub fn test_accept_payment(
+    wallet: &impl rpc::BitcoinRpc,
+    payees: &[(Address, Amount)],
+    options: &Options,
+) -> bitcoincore_rpc::Result<bitcoincore_rpc::json::TestMempoolAcceptResult> {
+    let signed = match options.fee {
+        Some(fee) => absolute_fee_transaction(wallet, payees, fee, options)?,
+        None => {
+            let funded = fund_payment(wallet, payees, options)?;
+            let signed = wallet.sign_raw_transaction_with_wallet(&funded.hex, None, None)?;
+            if !signed.complete {
+                let unsigned = funded.hex.to_lower_hex_string();
+                return Err(incomplete_signing_error(wallet, &unsigned, signed)?);
+            }
+            signed.hex
+        }
+    };
+    wallet
+        .test_mempool_accept(&[&signed])?
+        .pop()
+        .ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError(
+                "testmempoolaccept returned no result".to_string(),
+            )
+        })
+}
+
+// Pay each of `payees` in one transaction with the send settings from
+// `options`, through `send` and the `SendOptions` matching them. An absolute
+// --fee goes through the raw transaction path instead, since `send` only takes
+// rates. With --comment or --comment-to it's `sendtoaddress`, or `sendmany`
+// for several payees (which has no comment-to).
+pub fn send_payment(
+    wallet: &impl rpc::BitcoinRpc,
+    payees: &[(Address, Amount)],
+    options: &Options,
+) -> bitcoincore_rpc::Result<Txid> {
+    if let Some(fee) = options.fee {
+        let signed = absolute_fee_transaction(wallet, payees, fee, options)?;
+        return Ok(broadcast_checked(wallet, &signed)?);
+    }
+    if options.comment.is_none() && options.comment_to.is_none() {
+        return send(wallet, payees, &send_options(payees.len(), options));
+    }
+    // `send` can't store wallet comments, so a commented payment still goes
+    // through `sendtoaddress` or `sendmany`
+    let subtract_from: Vec<String> = payees
+        .iter()
+        .filter(|_| options.subtract_fee)
+        .map(|(address, _)| address.to_string())
+        .collect();
+    let [(address, amount)] = payees else {
+        let amounts: serde_json::Map<String, serde_json::Value> = payees
+            .iter()
+            .map(|(address, amount)| (address.to_string(), json!(amount.to_btc())))
+            .collect();
+        let args = [
+            json!(""),                    // dummy, must be empty
+            json!(amounts),               // address -> amount
+            json!(null),                  // minconf
+            json!(options.comment),       // comment, stored in the wallet
+            json!(subtract_from),         // addresses sharing the fee
+            json!(options.replaceable),   // signal BIP 125 replaceability
+            json!(null),                  // conf target
+            json!(options.estimate_mode), // estimate mode
+            json!(options.fee_rate),      // fee rate in sat/vB
+        ];
+        return wallet
+            .call("sendmany", &args)
+            .map_err(unsigned_payment_error);
+    };
+    let args = [
+        json!(address.to_string()),   // recipient address
+        json!(amount.to_btc()),       // amount
+        json!(options.comment),       // comment, stored in the wallet
+        json!(options.comment_to),    // who the payment is to, stored in the wallet
+        json!(options.subtract_fee),  // subtract fee from amount
+        json!(options.replaceable),   // signal BIP 125 replaceability
+        json!(null),                  // conf target
+        json!(options.estimate_mode), // estimate mode
+        json!(null),                  // avoid reuse
+        json!(options.fee_rate),      // fee rate in sat/vB
+    ];
+    wallet
+        .call("sendtoaddress", &args)
+        .map_err(unsigned_payment_error)
+}
+
+// `sendtoaddress` and `sendmany` only go through if the wallet signs on its
+// own, and give no PSBT when it can't. Say how to get one instead.
+fn unsigned_payment_error(error: bitcoincore_rpc::Error) -> bitcoincore_rpc::Error {
+    match rpc_error_message(&error) {
+        Some(message)
+            if message.contains("Private keys are disabled")
+                || message.contains("Signing transaction failed") =>
+        {
+            bitcoincore_rpc::Error::ReturnedError(format!(
+                "The wallet could not fully sign the payment ({message}); \
+                 send it without --comment/--comment-to to get the PSBT back, \
+                 or use --psbt"
+            ))
+        }
+        _ => error,
+    }
+}
+
+// The `send` settings asked for on the command line, for `payee_count`
+// recipients
+pub fn send_options(payee_count: usize, options: &Options) -> SendOptions {
+    let mut send_options = SendOptions::new().replaceable(options.replaceable);
+    if let Some(mode) = options.estimate_mode {
+        send_options = send_options.estimate_mode(mode);
+    }
+    if let Some(rate) = options.fee_rate {
+        send_options = send_options.fee_rate(rate);
+    }
+    if options.subtract_fee {
+        send_options = send_options.subtract_fee_from_outputs((0..payee_count as u32).collect());
+    }
+    send_options
+}
+
+// What `psbtbumpfee` returns, with `txid` filled in by `bump_fee` once the
+// replacement is broadcast. Until then `psbt` is what still needs signing.
+#[derive(Deserialize)]
+pub struct BumpFeeResult {
+    pub txid: Option<Txid>,
+    pub psbt: Option<String>,
+    #[serde(with = "as_btc")]
+    pub origfee: Amount,
+    #[serde(with = "as_btc")]
+    pub fee: Amount,
+    #[serde(default)]
+    pub errors: Vec<String>,
+}
+
+// Replace the wallet's unconfirmed `txid` with one paying more fee, at
+// `fee_rate` sat/vB or whatever the wallet estimates. Needs the original to
+// have signalled replaceability. The replacement is built as a PSBT, signed by
+// the wallet and goes out through `broadcast_checked`. If the wallet can't sign
+// it all, nothing is broadcast and the result carries the PSBT instead.
+pub fn bump_fee(
+    wallet: &impl rpc::BitcoinRpc,
+    txid: &Txid,
+    fee_rate: Option<f64>,
+) -> bitcoincore_rpc::Result<BumpFeeResult> {
+    let mut bump_options = serde_json::Map::new();
+    if let Some(rate) = fee_rate {
+        bump_options.insert("fee_rate".to_string(), json!(rate));
+    }
+    let args = [json!(txid.to_string()), json!(bump_options)];
+    let mut bumped: BumpFeeResult = wallet.call("psbtbumpfee", &args)?;
+    let Some(psbt) = &bumped.psbt else {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "psbtbumpfee returned no PSBT".to_string(),
+        ));
+    };
+    let signed = sign_psbt(wallet, psbt)?;
+    bumped.psbt = Some(signed.psbt.clone());
+    if !signed.complete {
+        return Ok(bumped);
+    }
+    let (_, raw) = finalize_psbt(wallet, &signed.psbt)?;
+    bumped.txid = Some(broadcast_checked(wallet, &raw)?);
+    Ok(bumped)
+}
+
+// Package fee rate `cpfp` aims for when no --fee-rate is given, in sat/vB.
+// Well above what regtest parents pay, so the effect is easy to see.
+pub const CPFP_DEFAULT_FEE_RATE: f64 = 50.0;
+
+// Virtual size of a one-input one-output segwit child, close enough for the fee
+const CPFP_CHILD_VSIZE: u64 = 110;
+
+// Child pays for parent: spend the output the wallet received in the
+// unconfirmed `txid` back to a fresh address of its own, with a fee big enough
+// to lift parent and child together to `fee_rate` sat/vB. Miners select by
+// that package rate, so a parent sent too cheaply confirms along with the
+// child.
+pub fn cpfp(
+    wallet: &impl rpc::BitcoinRpc,
+    txid: &Txid,
+    fee_rate: f64,
+) -> bitcoincore_rpc::Result<Txid> {
+    let parent = wallet.get_transaction(txid, None)?;
+    if parent.info.confirmations != 0 {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "{txid} already has {} confirmations, there is nothing to accelerate",
+            parent.info.confirmations
+        )));
+    }
+    let received = parent
+        .details
+        .iter()
+        .find(|d| d.category == bitcoincore_rpc::json::GetTransactionResultDetailCategory::Receive)
+        .ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError(format!(
+                "The wallet received nothing in {txid} that it could spend"
+            ))
+        })?;
+    let value = received.amount.to_unsigned().map_err(|e| {
+        bitcoincore_rpc::Error::ReturnedError(format!("Invalid received amount: {e}"))
+    })?;
+
+    // The parent already pays for its own vbytes, the child covers the rest
+    let entry = wallet.get_mempool_entry(txid)?;
+    let package_vsize = entry.vsize + CPFP_CHILD_VSIZE;
+    let package_fee = Amount::from_sat((fee_rate * package_vsize as f64).ceil() as u64);
+    let child_fee = package_fee
+        .checked_sub(entry.fees.base)
+        .unwrap_or(Amount::ZERO)
+        .max(Amount::from_sat(
+            (fee_rate * CPFP_CHILD_VSIZE as f64).ceil() as u64,
+        ));
+    let address = wallet.get_new_address(Some("CPFP"), None)?.assume_checked();
+    let child_value = value
+        .checked_sub(child_fee)
+        .filter(|v| {
+            let output = TxOut {
+                value: *v,
+                script_pubkey: address.script_pubkey(),
+            };
+            !is_dust(&output, DUST_RELAY_FEE)
+        })
+        .ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError(format!(
+                "Output {txid}:{} holds {} BTC, too little to pay a child fee of {} BTC",
+                received.vout,
+                value.to_btc(),
+                child_fee.to_btc()
+            ))
+        })?;
+    status!(
+        "Spending {txid}:{} ({} BTC) with a child fee of {} BTC",
+        received.vout,
+        value.to_btc(),
+        child_fee.to_btc()
+    );
+
+    let input = bitcoincore_rpc::json::CreateRawTransactionInput {
+        txid: *txid,
+        vout: received.vout,
+        sequence: None,
+    };
+    let mut outputs = HashMap::new();
+    outputs.insert(address.to_string(), child_value);
+    let unsigned = wallet.create_raw_transaction_hex(&[input], &outputs, None, Some(true))?;
+    let signed = wallet.sign_raw_transaction_with_wallet(unsigned.as_str(), None, None)?;
+    if !signed.complete {
+        return Err(incomplete_signing_error(wallet, &unsigned, signed)?);
+    }
+    match broadcast_checked(wallet, &signed.hex) {
+        Err(BroadcastError::Rejected(MempoolRejection::FeeTooLow(reason))) => {
+            Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "The child pays too little at {fee_rate} sat/vB ({reason}), \
+                 try a higher --fee-rate"
+            )))
+        }
+        result => Ok(result?),
+    }
+}
+
+// The comment and comment-to the wallet stored for `txid`, if any. The typed
+// `get_transaction` result drops these fields.
+pub fn transaction_comments(
+    wallet: &impl rpc::BitcoinRpc,
+    txid: &Txid,
+) -> bitcoincore_rpc::Result<(Option<String>, Option<String>)> {
+    #[derive(Deserialize)]
+    struct Comments {
+        comment: Option<String>,
+        to: Option<String>,
+    }
+    let comments = wallet.call::<Comments>("gettransaction", &[json!(txid)])?;
+    Ok((comments.comment, comments.to))
+}
+
+// Whether a failed send would succeed once the wallet's unconfirmed coins
+// confirm: it reported insufficient funds although confirmed plus pending
+// balance covers `amount`
+pub fn needs_confirmed_funds(
+    wallet: &impl rpc::BitcoinRpc,
+    error: &bitcoincore_rpc::Error,
+    amount: Amount,
+) -> bitcoincore_rpc::Result<bool> {
+    if !rpc_error_message(error).is_some_and(|m| m.contains("Insufficient funds")) {
+        return Ok(false);
+    }
+    let balances = wallet.get_balances()?.mine;
+    Ok(balances.untrusted_pending > Amount::ZERO
+        && balances.trusted + balances.untrusted_pending >= amount)
+}
+
+// Compare the fee rate the transaction actually pays with the one we asked for.
+// Wallets round up for signature size estimates, so some slack is expected.
+pub fn assert_fee_rate(actual: f64, requested: f64, tolerance: f64) -> bool {
+    let deviation = (actual - requested).abs() / requested;
+    status!(
+        "Fee rate: requested {requested:.2} sat/vB, actual {actual:.2} sat/vB ({:.1}% off)",
+        deviation * 100.0
+    );
+    if deviation > tolerance {
+        status!(
+            "Warning: the wallet's fee rate is more than {:.0}% off the requested rate",
+            tolerance * 100.0
+        );
+        return false;
+    }
+    true
+}
+
+// Sanity guard against fat-fingered fee rates. Warns whenever the fee is above
+// `max_fee` and refuses to continue unless the user passed `--yes`.
+pub fn verify_fee_within_limit(
+    fee: Amount,
+    max_fee: Amount,
+    yes: bool,
+) -> bitcoincore_rpc::Result<()> {
+    if fee <= max_fee {
+        return Ok(());
+    }
+
+    status!(
+        "Warning: fee of {} BTC exceeds the maximum of {} BTC",
+        fee.to_btc(),
+        max_fee.to_btc()
+    );
+    if yes {
+        status!("Continuing anyway because --yes was given");
+        return Ok(());
+    }
+    Err(bitcoincore_rpc::Error::ReturnedError(format!(
+        "Fee of {} BTC exceeds --max-fee {} BTC; re-run with --yes to send anyway",
+        fee.to_btc(),
+        max_fee.to_btc()
+    )))
+}
+
+// Refuse a --fee-rate the node would reject anyway. Both minimums come in
+// BTC/kvB; the mempool one rises above the relay fee when the mempool is full.
+pub fn check_relay_minimum(
+    rpc: &impl rpc::BitcoinRpc,
+    fee_rate: f64,
+) -> bitcoincore_rpc::Result<()> {
+    let mempool_min = rpc.get_mempool_info()?.mempool_min_fee;
+    let relay_min = rpc.get_network_info()?.relay_fee;
+    let minimum = mempool_min.max(relay_min).to_sat() as f64 / 1000.0;
+    if fee_rate < minimum {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Fee rate {fee_rate} sat/vB is below the node's minimum of {minimum} sat/vB \
+             (mempool minimum {} BTC/kvB, relay fee {} BTC/kvB)",
+            mempool_min.to_btc(),
+            relay_min.to_btc()
+        )));
+    }
+    Ok(())
+}
+
+// Everything the `send` RPC takes besides the recipients, built up with the
+// setters below. Whatever is left unset is up to the wallet.
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    conf_target: Option<u32>,
+    estimate_mode: Option<EstimateMode>,
+    fee_rate: Option<f64>,
+    change_address: Option<Address>,
+    change_position: Option<u32>,
+    replaceable: Option<bool>,
+    locktime: Option<u32>,
+    subtract_fee_from_outputs: Vec<u32>,
+}
+
+impl SendOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Blocks within which the transaction should confirm, for fee estimation
+    pub fn conf_target(mut self, blocks: u32) -> Self {
+        self.conf_target = Some(blocks);
+        self
+    }
+
+    pub fn estimate_mode(mut self, mode: EstimateMode) -> Self {
+        self.estimate_mode = Some(mode);
+        self
+    }
+
+    // In sat/vB
+    pub fn fee_rate(mut self, rate: f64) -> Self {
+        self.fee_rate = Some(rate);
+        self
+    }
+
+    pub fn change_address(mut self, address: Address) -> Self {
+        self.change_address = Some(address);
+        self
+    }
+
+    pub fn change_position(mut self, position: u32) -> Self {
+        self.change_position = Some(position);
+        self
+    }
+
+    // Signal BIP 125 replaceability
+    pub fn replaceable(mut self, replaceable: bool) -> Self {
+        self.replaceable = Some(replaceable);
+        self
+    }
+
+    pub fn locktime(mut self, locktime: u32) -> Self {
+        self.locktime = Some(locktime);
+        self
+    }
+
+    // Indices into the recipients of the outputs that pay the fee between them
+    pub fn subtract_fee_from_outputs(mut self, outputs: Vec<u32>) -> Self {
+        self.subtract_fee_from_outputs = outputs;
+        self
+    }
+
+    // The trailing `options` object of `send`, holding the settings that have
+    // no positional parameter
+    fn options_object(&self) -> serde_json::Value {
+        let mut object = serde_json::Map::new();
+        if let Some(address) = &self.change_address {
+            object.insert("change_address".to_string(), json!(address.to_string()));
+        }
+        if let Some(position) = self.change_position {
+            object.insert("change_position".to_string(), json!(position));
+        }
+        if let Some(replaceable) = self.replaceable {
+            object.insert("replaceable".to_string(), json!(replaceable));
+        }
+        if let Some(locktime) = self.locktime {
+            object.insert("locktime".to_string(), json!(locktime));
+        }
+        if !self.subtract_fee_from_outputs.is_empty() {
+            object.insert(
+                "subtract_fee_from_outputs".to_string(),
+                json!(self.subtract_fee_from_outputs),
+            );
+        }
+        serde_json::Value::Object(object)
+    }
+}
+
+// What `send` returns. A wallet that can't sign on its own (e.g. one cosigner
+// of a multisig) doesn't broadcast, and hands back the PSBT instead of a txid.
+#[derive(Deserialize, Debug)]
+pub struct SendResult {
+    pub complete: bool,
+    pub txid: Option<Txid>,
+    pub hex: Option<String>,
+    pub psbt: Option<String>,
+}
+
+// Pay `outputs` in one transaction through the `send` RPC, which has no typed
+// wrapper. A send the wallet couldn't fully sign was not broadcast, and comes
+// back as an error carrying the PSBT for the other signers.
+pub fn send(
+    rpc: &impl rpc::BitcoinRpc,
+    outputs: &[(Address, Amount)],
+    options: &SendOptions,
+) -> bitcoincore_rpc::Result<Txid> {
+    let outputs: Vec<_> = outputs
+        .iter()
+        .map(|(address, amount)| json!({ address.to_string(): amount.to_btc() }))
+        .collect();
+    let args = [
+        json!(outputs),               // recipients
+        json!(options.conf_target),   // conf target
+        json!(options.estimate_mode), // estimate mode
+        json!(options.fee_rate),      // fee rate in sat/vB
+        options.options_object(),     // everything else
+    ];
+    let result: SendResult = rpc.call("send", &args)?;
+    if !result.complete {
+        let next_step = match &result.psbt {
+            Some(psbt) => format!("PSBT needing further signatures: {psbt}"),
+            None => "no PSBT was returned".to_string(),
+        };
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "send did not complete, the wallet could not fully sign the transaction; \
+             {next_step}"
+        )));
+    }
+    result.txid.ok_or_else(|| {
+        bitcoincore_rpc::Error::ReturnedError("send reported completion without a txid".to_string())
+    })
+}
+
+// Mempool entry for `txid`, checked against the transaction itself. The entry
+// only echoes the wtxid, so compare it with the wtxid of the transaction the
+// node returns for `txid`.
+pub fn verified_mempool_entry(
+    rpc: &impl rpc::BitcoinRpc,
+    txid: &Txid,
+) -> bitcoincore_rpc::Result<bitcoincore_rpc::json::GetMempoolEntryResult> {
+    let entry = rpc.get_mempool_entry(txid)?;
+    let tx = rpc.get_raw_transaction_info(txid, None)?;
+    if tx.txid != *txid || tx.hash.to_string() != entry.wtxid.to_string() {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Mempool entry for {txid} belongs to a different transaction \
+             (entry wtxid {}, transaction wtxid {})",
+            entry.wtxid, tx.hash
+        )));
+    }
+    Ok(entry)
+}
+
+// Where a transaction we sent currently stands
+pub enum TxStatus {
+    InMempool,
+    Confirmed,
+    // Conflicted out (confirmations == -1), most likely by an RBF fee bump.
+    // `by` is the replacement if the wallet knows about it.
+    Replaced { by: Option<Txid> },
+    Unknown,
+}
+
+// Work out what happened to `txid`, which `wallet` sent. Used when the mempool
+// lookup fails so we can tell a replacement apart from other problems.
+pub fn tx_status(
+    rpc: &impl rpc::BitcoinRpc,
+    wallet: &impl rpc::BitcoinRpc,
+    txid: &Txid,
+) -> bitcoincore_rpc::Result<TxStatus> {
+    if rpc.get_mempool_entry(txid).is_ok() {
+        return Ok(TxStatus::InMempool);
+    }
+
+    let tx = wallet.get_transaction(txid, None)?;
+    if tx.info.confirmations > 0 {
+        return Ok(TxStatus::Confirmed);
+    }
+    if tx.info.confirmations == 0 {
+        return Ok(TxStatus::Unknown);
+    }
+
+    // The replacement lists the original among its own conflicts and, unlike
+    // the original, is not itself conflicted
+    let since = wallet.list_since_block(None, None, None, None)?;
+    let replacement = since
+        .transactions
+        .iter()
+        .find(|t| t.info.confirmations >= 0 && t.info.wallet_conflicts.contains(txid))
+        .map(|t| t.info.txid)
+        .or_else(|| tx.info.wallet_conflicts.first().copied());
+    Ok(TxStatus::Replaced { by: replacement })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{PubkeyHash, ScriptBuf, WPubkeyHash};
+
+    fn output(sats: u64, script_pubkey: ScriptBuf) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey,
+        }
+    }
+
+    #[test]
+    fn is_dust_matches_the_node_thresholds() {
+        let p2wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        assert!(is_dust(&output(293, p2wpkh.clone()), DUST_RELAY_FEE));
+        assert!(!is_dust(&output(294, p2wpkh), DUST_RELAY_FEE));
+
+        let p2pkh = ScriptBuf::new_p2pkh(&PubkeyHash::all_zeros());
+        assert!(is_dust(&output(545, p2pkh.clone()), DUST_RELAY_FEE));
+        assert!(!is_dust(&output(546, p2pkh), DUST_RELAY_FEE));
+    }
+
+    #[test]
+    fn is_dust_never_flags_op_return() {
+        let data = ScriptBuf::new_op_return([0xab; 4]);
+        assert!(!is_dust(&output(0, data), DUST_RELAY_FEE));
+    }
+
+    #[test]
+    fn mempool_rejection_sorts_node_reasons() {
+        let cases = [
+            ("min relay fee not met, 100 < 141", "FeeTooLow"),
+            ("mempool min fee not met", "FeeTooLow"),
+            ("insufficient fee, rejecting replacement", "FeeTooLow"),
+            ("max-fee-exceeded", "FeeTooHigh"),
+            ("absurdly-high-fee", "FeeTooHigh"),
+            ("non-final", "NonFinal"),
+            ("non-BIP68-final", "NonFinal"),
+            ("txn-mempool-conflict", "Conflict"),
+            ("bad-txns-inputs-missingorspent", "MissingInputs"),
+            ("missing-inputs", "MissingInputs"),
+            ("bad-txns-vout-empty", "Other"),
+        ];
+        for (reason, kind) in cases {
+            let rejection = MempoolRejection::from_reason(reason);
+            let (name, kept) = match &rejection {
+                MempoolRejection::FeeTooLow(r) => ("FeeTooLow", r),
+                MempoolRejection::FeeTooHigh(r) => ("FeeTooHigh", r),
+                MempoolRejection::NonFinal(r) => ("NonFinal", r),
+                MempoolRejection::Conflict(r) => ("Conflict", r),
+                MempoolRejection::MissingInputs(r) => ("MissingInputs", r),
+                MempoolRejection::Other(r) => ("Other", r),
+            };
+            assert_eq!(name, kind, "{reason}");
+            assert_eq!(kept, reason);
+        }
+    }
+}
@@ -0,0 +1,618 @@
+// Creating, loading and checking the Miner and Trader wallets, and the state
+// kept about them between runs.
+
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::bip32::Xpriv;
+use bitcoincore_rpc::bitcoin::hashes::{sha256, Hash};
+use bitcoincore_rpc::bitcoin::{Address, Network};
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+
+use crate::*;
+
+// Whether a failed `loadwallet` means the wallet is in fact loaded already,
+// e.g. by another RPC client between our `listwallets` and `loadwallet`. The
+// wallet is usable then, unlike after a genuine load failure.
+pub fn wallet_already_loaded(error: &bitcoincore_rpc::Error) -> bool {
+    rpc_error_message(error)
+        .is_some_and(|m| m.contains("already loaded") || m.contains("already loading"))
+}
+
+// Sign `message` with the key behind `address`, which must belong to `wallet`.
+// Neither `signmessage` nor a base64 `verifymessage` have typed wrappers.
+pub fn sign_message(
+    wallet: &impl rpc::BitcoinRpc,
+    address: &str,
+    message: &str,
+) -> bitcoincore_rpc::Result<String> {
+    wallet
+        .call("signmessage", &[json!(address), json!(message)])
+        .map_err(|e| match rpc_error_message(&e) {
+            Some(m) if m.contains("Private key not available") => {
+                bitcoincore_rpc::Error::ReturnedError(format!(
+                    "Address {address} does not belong to this wallet, so it can't sign for it"
+                ))
+            }
+            Some(m) if m.contains("does not refer to key") => {
+                bitcoincore_rpc::Error::ReturnedError(format!(
+                    "Address {address} is not a legacy (P2PKH) address, only those can sign messages"
+                ))
+            }
+            _ => e,
+        })
+}
+
+pub fn verify_message(
+    rpc: &impl rpc::BitcoinRpc,
+    address: &str,
+    message: &str,
+    signature: &str,
+) -> bitcoincore_rpc::Result<bool> {
+    rpc.call(
+        "verifymessage",
+        &[json!(address), json!(signature), json!(message)],
+    )
+}
+
+// Balance of a descriptor straight from the UTXO set, for watch-only setups
+// that have no wallet on the node at all
+pub fn scan_balance(rpc: &impl rpc::BitcoinRpc, descriptor: &str) -> bitcoincore_rpc::Result<()> {
+    // Ranged descriptors need an explicit derivation range to scan
+    let request = if descriptor.contains('*') {
+        bitcoincore_rpc::json::ScanTxOutRequest::Extended {
+            desc: descriptor.to_string(),
+            range: (0, 999),
+        }
+    } else {
+        bitcoincore_rpc::json::ScanTxOutRequest::Single(descriptor.to_string())
+    };
+
+    status!("Scanning the UTXO set, this can take a while on a large chain...");
+    let result = rpc.scan_tx_out_set_blocking(&[request])?;
+    if result.success == Some(false) {
+        return Err(bitcoincore_rpc::Error::ReturnedError(
+            "UTXO set scan did not complete".to_string(),
+        ));
+    }
+
+    if let Some(height) = result.height {
+        status!("Scanned UTXO set at height {height}");
+    }
+    status!("UTXOs found: {}", result.unspents.len());
+    status!("Total amount: {} BTC", result.total_amount.to_btc());
+    Ok(())
+}
+
+// Preview the next `count` receiving addresses of a descriptor wallet without
+// handing them out. `listdescriptors` has no typed wrapper, so we use `call`.
+pub fn preview_addresses(
+    wallet: &impl rpc::BitcoinRpc,
+    count: u32,
+) -> bitcoincore_rpc::Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Descriptor {
+        desc: String,
+        active: bool,
+        internal: Option<bool>,
+        next: Option<u32>,
+    }
+    #[derive(Deserialize)]
+    struct ListDescriptorsResult {
+        descriptors: Vec<Descriptor>,
+    }
+
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let result = wallet.call::<ListDescriptorsResult>("listdescriptors", &[])?;
+    // We always ask for bech32 addresses, so the receiving descriptor is the
+    // active, non-internal wpkh() one
+    let receiving = result
+        .descriptors
+        .iter()
+        .find(|d| d.active && d.internal != Some(true) && d.desc.starts_with("wpkh("))
+        .ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError(
+                "Wallet has no active receiving wpkh() descriptor".to_string(),
+            )
+        })?;
+
+    let start = receiving.next.unwrap_or(0);
+    let addresses = wallet.derive_addresses(&receiving.desc, Some([start, start + count - 1]))?;
+    Ok(addresses
+        .into_iter()
+        .map(|a| a.assume_checked().to_string())
+        .collect())
+}
+
+// How to create a wallet that doesn't exist on the node yet
+pub struct WalletSetup {
+    pub watch_only: bool,
+    pub blank: bool,
+    pub keypool_size: Option<usize>,
+    // Import the fixed --deterministic keys after creating it
+    pub deterministic: bool,
+}
+
+// Make sure wallet `name` is loaded: load it if it exists on disk, otherwise
+// create it as `setup` describes. Another client loading it at the same time
+// is as good as loading it ourselves.
+pub fn ensure_wallet(
+    rpc: &impl rpc::BitcoinRpc,
+    url: &str,
+    auth: &Auth,
+    name: &str,
+    setup: &WalletSetup,
+) -> bitcoincore_rpc::Result<()> {
+    if rpc.list_wallets()?.iter().any(|loaded| loaded == name) {
+        return Ok(());
+    }
+    match rpc.load_wallet(name) {
+        Ok(_) => status!("Loaded existing {name} wallet"),
+        Err(e) if wallet_already_loaded(&e) => status!("{name} wallet is already loaded"),
+        Err(_) => {
+            match rpc.create_wallet(name, Some(setup.watch_only), Some(setup.blank), None, None) {
+                Ok(_) => {
+                    status!("Created new {name} wallet");
+                    if let Some(size) = setup.keypool_size {
+                        refill_keypool(&wallet_client(url, name, auth)?, name, size)?;
+                    }
+                    if setup.deterministic {
+                        seed_deterministic_wallet(url, name, auth)?;
+                    }
+                }
+                // Try loading again - wallet exists but wasn't loaded
+                Err(_) => match rpc.load_wallet(name) {
+                    Err(e) if !wallet_already_loaded(&e) => return Err(e),
+                    _ => status!("Loaded existing {name} wallet on retry"),
+                },
+            }
+        }
+    }
+    Ok(())
+}
+
+// How often and how patiently to reconnect to a wallet that stopped responding
+pub const RECONNECT_ATTEMPTS: u32 = 10;
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+// Reconnect to `wallet_name`, e.g. after the node restarted. A restarted node
+// only reloads wallets flagged to load on startup, so load it again as well.
+pub fn connect_wallet_with_retry(
+    url: &str,
+    wallet_name: &str,
+    auth: &Auth,
+) -> bitcoincore_rpc::Result<Client> {
+    let mut last_error = None;
+    for attempt in 1..=RECONNECT_ATTEMPTS {
+        if attempt > 1 {
+            sleep_unless_interrupted(RECONNECT_DELAY);
+        }
+        let node = rpc_client(url, auth)?;
+        match node.load_wallet(wallet_name) {
+            Ok(_) => {}
+            Err(e) if wallet_already_loaded(&e) => {}
+            Err(e) => {
+                debug(&format!(
+                    "Reconnect attempt {attempt} to {wallet_name}: {e}"
+                ));
+                last_error = Some(e);
+                continue;
+            }
+        }
+        let wallet = wallet_client(url, wallet_name, auth)?;
+        match wallet.get_wallet_info() {
+            Ok(_) => return Ok(wallet),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        bitcoincore_rpc::Error::ReturnedError(format!("Could not reconnect to {wallet_name}"))
+    }))
+}
+
+// Cheap health check of a wallet client; replace it with a fresh connection
+// if the wallet stopped answering
+pub fn refresh_wallet_client(
+    url: &str,
+    wallet: Client,
+    wallet_name: &str,
+    auth: &Auth,
+) -> bitcoincore_rpc::Result<Client> {
+    if wallet.get_wallet_info().is_ok() {
+        return Ok(wallet);
+    }
+    status!("{wallet_name} wallet stopped responding, reconnecting");
+    connect_wallet_with_retry(url, wallet_name, auth)
+}
+
+// Catch wallet setups the run can't work with before any blocks get mined:
+// the Miner has to sign the payment, and the Trader needs something to derive
+// its receiving address from.
+pub fn validate_wallets(
+    miner: &impl rpc::BitcoinRpc,
+    trader: Option<&impl rpc::BitcoinRpc>,
+    options: &Options,
+) -> bitcoincore_rpc::Result<()> {
+    let miner_info = miner.get_wallet_info()?;
+    if !miner_info.private_keys_enabled {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Wallet {} has private keys disabled and can't sign the payment",
+            miner_info.wallet_name
+        )));
+    }
+
+    // No Trader under --no-send
+    let Some(trader) = trader else {
+        return Ok(());
+    };
+    let trader_info = trader.get_wallet_info()?;
+    if options.trader_watch_only && trader_info.private_keys_enabled {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "--trader-watch-only was given but wallet {} already exists with private keys",
+            trader_info.wallet_name
+        )));
+    }
+    if trader_info.keypool_size == 0 {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Wallet {} has no keys or descriptors to derive a receiving address from{}",
+            trader_info.wallet_name,
+            if options.trader_blank || options.trader_watch_only {
+                " (a blank or watch-only Trader needs descriptors imported first)"
+            } else {
+                ""
+            }
+        )));
+    }
+    Ok(())
+}
+
+// --keypool-size: top up a legacy wallet's keypool so that handing out many
+// addresses can't exhaust it mid-run. Descriptor wallets derive addresses on
+// demand and wallets without private keys have no keypool to fill.
+pub fn refill_keypool(
+    wallet: &impl rpc::BitcoinRpc,
+    wallet_name: &str,
+    size: usize,
+) -> bitcoincore_rpc::Result<()> {
+    // The typed `get_wallet_info` result has no `descriptors` field
+    #[derive(Deserialize)]
+    struct WalletKind {
+        descriptors: Option<bool>,
+        private_keys_enabled: bool,
+    }
+    let kind = wallet.call::<WalletKind>("getwalletinfo", &[])?;
+    if kind.descriptors == Some(true) || !kind.private_keys_enabled {
+        debug(&format!(
+            "Not refilling the keypool of {wallet_name}, it is a descriptor or watch-only wallet"
+        ));
+        return Ok(());
+    }
+    wallet.key_pool_refill(Some(size))?;
+    status!("Refilled the {wallet_name} keypool to {size} keys");
+    Ok(())
+}
+
+// Make `descriptor` one of the wallet's active descriptors, so that
+// `get_new_address` (or the change logic, if `internal`) derives addresses from it
+pub fn import_active_descriptor(
+    wallet: &impl rpc::BitcoinRpc,
+    descriptor: &str,
+    internal: bool,
+) -> bitcoincore_rpc::Result<()> {
+    let info = wallet.get_descriptor_info(descriptor)?;
+    if !info.is_range {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Descriptor must be ranged (use /* in the key paths) to derive addresses: {descriptor}"
+        )));
+    }
+    let descriptor = match info.checksum {
+        Some(checksum) if !descriptor.contains('#') => format!("{descriptor}#{checksum}"),
+        _ => descriptor.to_string(),
+    };
+
+    let results = wallet.import_descriptors(bitcoincore_rpc::json::ImportDescriptors {
+        descriptor,
+        timestamp: bitcoincore_rpc::json::Timestamp::Now,
+        active: Some(true),
+        range: Some((0, 999)),
+        next_index: None,
+        internal: Some(internal),
+        label: None,
+    })?;
+    for result in results {
+        if !result.success {
+            return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "Failed to import descriptor: {:?}",
+                result.error
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Receiving and change descriptors derived from a master key that is fixed per
+// wallet name, so that --deterministic runs on a fresh chain always use the same
+// addresses. These keys are public knowledge: only ever use them on regtest.
+pub fn deterministic_descriptors(wallet_name: &str) -> (String, String) {
+    let seed = sha256::Hash::hash(format!("rust-capstone-project/{wallet_name}").as_bytes());
+    let master = Xpriv::new_master(Network::Regtest, seed.as_byte_array())
+        .expect("a 32 byte seed is always a valid master key");
+    (
+        format!("wpkh({master}/84h/1h/0h/0/*)"),
+        format!("wpkh({master}/84h/1h/0h/1/*)"),
+    )
+}
+
+// Seed a freshly created blank wallet with its deterministic descriptors
+pub fn seed_deterministic_wallet(
+    url: &str,
+    wallet_name: &str,
+    auth: &Auth,
+) -> bitcoincore_rpc::Result<()> {
+    let wallet = wallet_client(url, wallet_name, auth)?;
+    let (receiving, change) = deterministic_descriptors(wallet_name);
+    import_active_descriptor(&wallet, &receiving, false)?;
+    import_active_descriptor(&wallet, &change, true)?;
+    status!("Seeded {wallet_name} wallet with deterministic descriptors");
+    Ok(())
+}
+
+// The address type `get_new_address` has to ask for to use `descriptor`
+pub fn descriptor_address_type(descriptor: &str) -> bitcoincore_rpc::json::AddressType {
+    if descriptor.starts_with("sh(wsh(") {
+        bitcoincore_rpc::json::AddressType::P2shSegwit
+    } else if descriptor.starts_with("sh(") {
+        bitcoincore_rpc::json::AddressType::Legacy
+    } else {
+        bitcoincore_rpc::json::AddressType::Bech32
+    }
+}
+
+// Defend against wallet context mix-ups (e.g. the wrong per-wallet URL) by
+// checking the wallet really controls an address it just handed out.
+// Watch-only wallets only watch their addresses, which is fine for receiving.
+pub fn ensure_owned(
+    wallet: &impl rpc::BitcoinRpc,
+    address: &Address,
+    wallet_name: &str,
+) -> bitcoincore_rpc::Result<()> {
+    let info = wallet.get_address_info(address)?;
+    if info.is_mine == Some(true) || info.is_watchonly == Some(true) {
+        return Ok(());
+    }
+    Err(bitcoincore_rpc::Error::ReturnedError(format!(
+        "Address {address} is not owned by the {wallet_name} wallet"
+    )))
+}
+
+// The two getaddressinfo flags change detection needs. `ischange` has no
+// field on the typed result, so this goes through `call`.
+#[derive(Deserialize)]
+pub struct AddressOwnership {
+    #[serde(rename = "ismine")]
+    pub is_mine: bool,
+    // Derived from the wallet's internal keychain; absent for foreign addresses
+    #[serde(rename = "ischange", default)]
+    pub is_change: bool,
+}
+
+pub fn address_ownership(
+    wallet: &impl rpc::BitcoinRpc,
+    address: &Address,
+) -> bitcoincore_rpc::Result<AddressOwnership> {
+    wallet.call("getaddressinfo", &[json!(address)])
+}
+
+// Wallets and addresses of an earlier run, kept in --state-file so re-runs
+// reuse them instead of handing out fresh addresses every time
+#[derive(Serialize, Deserialize)]
+pub struct RunState {
+    pub miner_wallet: String,
+    pub trader_wallet: String,
+    pub miner_address: Address<NetworkUnchecked>,
+    pub trader_address: Option<Address<NetworkUnchecked>>,
+}
+
+// The state saved at `path` for these wallets. A missing, unreadable or
+// foreign state file just means starting over with fresh addresses.
+pub fn load_state(path: &str, miner_wallet: &str, trader_wallet: &str) -> Option<RunState> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            status!("Warning: can't read state file {path}, starting fresh: {e}");
+            return None;
+        }
+    };
+    let state = match serde_json::from_str::<RunState>(&contents) {
+        Ok(state) => state,
+        Err(e) => {
+            status!("Warning: ignoring corrupt state file {path}: {e}");
+            return None;
+        }
+    };
+    if state.miner_wallet != miner_wallet || state.trader_wallet != trader_wallet {
+        status!(
+            "State file {path} belongs to wallets {} and {}, starting fresh",
+            state.miner_wallet,
+            state.trader_wallet
+        );
+        return None;
+    }
+    Some(state)
+}
+
+pub fn save_state(path: &str, state: &RunState) -> bitcoincore_rpc::Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(state)?)?;
+    Ok(())
+}
+
+// A saved address, if it is still valid on `network` and owned by the wallet
+pub fn reuse_address(
+    wallet: &impl rpc::BitcoinRpc,
+    saved: Option<&Address<NetworkUnchecked>>,
+    wallet_name: &str,
+    network: Network,
+) -> Option<Address<NetworkUnchecked>> {
+    let saved = saved?;
+    let checked = saved.clone().require_network(network).ok()?;
+    match ensure_owned(wallet, &checked, wallet_name) {
+        Ok(()) => {
+            status!("Reusing {wallet_name} address {checked} from the state file");
+            Some(saved.clone())
+        }
+        Err(e) => {
+            status!("Warning: not reusing saved address: {e}");
+            None
+        }
+    }
+}
+
+// Print a wallet's balances, including the watch-only part that legacy wallets
+// with imported watch-only addresses report separately
+pub fn print_balances(
+    wallet_name: &str,
+    wallet: &impl rpc::BitcoinRpc,
+) -> bitcoincore_rpc::Result<()> {
+    let balances = wallet.get_balances()?;
+    let describe = |entry: &bitcoincore_rpc::json::GetBalancesResultEntry| {
+        format!(
+            "trusted {} BTC, untrusted {} BTC, immature {} BTC",
+            entry.trusted.to_btc(),
+            entry.untrusted_pending.to_btc(),
+            entry.immature.to_btc()
+        )
+    };
+    status!("{wallet_name} balance: {}", describe(&balances.mine));
+    if let Some(watchonly) = &balances.watchonly {
+        status!("{wallet_name} watch-only balance: {}", describe(watchonly));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::json::AddressType;
+    use bitcoincore_rpc::jsonrpc::error::{Error as JsonRpcError, RpcError};
+
+    fn rpc_error(code: i32, message: &str) -> bitcoincore_rpc::Error {
+        bitcoincore_rpc::Error::JsonRpc(JsonRpcError::Rpc(RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }))
+    }
+
+    fn state_path(name: &str) -> String {
+        let file = format!("capstone-{name}-{}.json", std::process::id());
+        std::env::temp_dir()
+            .join(file)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn address(s: &str) -> Address<NetworkUnchecked> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn descriptor_address_type_follows_the_script_wrapper() {
+        assert!(matches!(
+            descriptor_address_type("sh(wsh(multi(2,A,B)))"),
+            AddressType::P2shSegwit
+        ));
+        assert!(matches!(
+            descriptor_address_type("sh(multi(2,A,B))"),
+            AddressType::Legacy
+        ));
+        assert!(matches!(
+            descriptor_address_type("wsh(multi(2,A,B))"),
+            AddressType::Bech32
+        ));
+        assert!(matches!(
+            descriptor_address_type("wpkh(A/0/*)"),
+            AddressType::Bech32
+        ));
+    }
+
+    #[test]
+    fn deterministic_descriptors_repeat_for_the_same_wallet() {
+        assert_eq!(
+            deterministic_descriptors("Miner"),
+            deterministic_descriptors("Miner")
+        );
+        assert_ne!(
+            deterministic_descriptors("Miner"),
+            deterministic_descriptors("Trader")
+        );
+    }
+
+    #[test]
+    fn deterministic_descriptors_split_receiving_and_change() {
+        let (receiving, change) = deterministic_descriptors("Miner");
+        assert!(receiving.starts_with("wpkh(tprv"));
+        assert!(receiving.ends_with("/84h/1h/0h/0/*)"));
+        assert!(change.ends_with("/84h/1h/0h/1/*)"));
+    }
+
+    #[test]
+    fn wallet_already_loaded_matches_the_load_race_errors() {
+        assert!(wallet_already_loaded(&rpc_error(
+            -35,
+            "Wallet \"Miner\" is already loaded."
+        )));
+        assert!(wallet_already_loaded(&rpc_error(
+            -4,
+            "Wallet already loading."
+        )));
+        assert!(!wallet_already_loaded(&rpc_error(
+            -18,
+            "Path does not exist."
+        )));
+        assert!(!wallet_already_loaded(
+            &bitcoincore_rpc::Error::ReturnedError("already loaded".to_string())
+        ));
+    }
+
+    #[test]
+    fn run_state_survives_a_save_and_load() {
+        let path = state_path("round-trip");
+        let miner = address("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080");
+        let trader = address("bcrt1q6rhpng9evdsfnn833a4f4vej0asu6dk5srld6x");
+        let state = RunState {
+            miner_wallet: "Miner".to_string(),
+            trader_wallet: "Trader".to_string(),
+            miner_address: miner.clone(),
+            trader_address: Some(trader.clone()),
+        };
+        save_state(&path, &state).unwrap();
+        let loaded = load_state(&path, "Miner", "Trader").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.miner_wallet, "Miner");
+        assert_eq!(loaded.trader_wallet, "Trader");
+        assert_eq!(loaded.miner_address, miner);
+        assert_eq!(loaded.trader_address, Some(trader));
+    }
+
+    #[test]
+    fn load_state_ignores_other_wallets_and_corrupt_files() {
+        let path = state_path("foreign");
+        let state = RunState {
+            miner_wallet: "Miner-a".to_string(),
+            trader_wallet: "Trader-a".to_string(),
+            miner_address: address("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"),
+            trader_address: None,
+        };
+        save_state(&path, &state).unwrap();
+        assert!(load_state(&path, "Miner", "Trader").is_none());
+        std::fs::write(&path, "{not json").unwrap();
+        assert!(load_state(&path, "Miner-a", "Trader-a").is_none());
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_state(&path, "Miner-a", "Trader-a").is_none());
+    }
+}
@@ -0,0 +1,46 @@
+#!/usr/bin/env bash
+set -e  # Exit immediately if any command fails
+
+# Runs the capstone flow twice with --deterministic, each time on a fresh regtest
+# chain, and checks that both runs produced the same addresses and amounts.
+# Only the txid (line 1) and the block height/hash (lines 9-10) may differ.
+
+start_bitcoind() {
+  docker compose up -d
+  sleep 10
+
+  echo "Waiting for bitcoind to be fully initialized..."
+
+  while true; do
+    result=$(curl --silent --user alice:password --data-binary \
+      '{"jsonrpc":"1.0","id":"ping","method":"getblockchaininfo","params":[]}' \
+      -H 'content-type: text/plain;' http://127.0.0.1:18443)
+
+    if echo "$result" | grep -q '"chain"'; then
+      echo "bitcoind is ready."
+      break
+    else
+      echo "bitcoind not ready yet, retrying in 3s..."
+      sleep 3
+    fi
+  done
+}
+
+run_deterministic() {
+  start_bitcoind
+  (cd rust && cargo run -- --deterministic)
+  cp out.txt "$1"
+  # Throw the chain and wallets away so the next run starts fresh
+  docker compose down -v
+}
+
+run_deterministic out.first.txt
+run_deterministic out.second.txt
+
+if diff <(sed -n '2,8p' out.first.txt) <(sed -n '2,8p' out.second.txt); then
+  echo "Deterministic runs match."
+  rm out.first.txt out.second.txt
+else
+  echo "Deterministic runs differ in the address or amount fields (see above)."
+  exit 1
+fi